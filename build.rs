@@ -0,0 +1,27 @@
+// SPDX-License-Identifier: GPL-2.0
+// Copyright 2026 Google LLC
+
+use std::env;
+use std::process::Command;
+
+// Record an identifier of the sources this firmware was built from, so that it can be reported
+// to the guest. Use EFILITE_BUILD_ID if set (e.g., for reproducible builds), or ask git otherwise.
+fn main() {
+    let id = env::var("EFILITE_BUILD_ID")
+        .ok()
+        .or_else(|| {
+            let out = Command::new("git")
+                .args(["describe", "--always", "--dirty", "--abbrev=12"])
+                .output()
+                .ok()
+                .filter(|o| o.status.success())?;
+            Some(String::from_utf8(out.stdout).ok()?.trim().to_string())
+        })
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    println!("cargo:rustc-env=EFILITE_BUILD_ID={}", id);
+    println!("cargo:rerun-if-env-changed=EFILITE_BUILD_ID");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+}
@@ -0,0 +1,84 @@
+// SPDX-License-Identifier: GPL-2.0
+// Copyright 2026 Google LLC
+
+use crate::fwcfg::{FwCfg, CFG_BOOT_SOURCE_KERNEL};
+use crate::{psci, timer};
+
+use alloc::string::*;
+use alloc::vec::Vec;
+use core::str::from_utf8;
+use log::{error, info};
+
+// Map an OpenFirmware device path from QEMU's bootorder file onto one of our boot sources. Note
+// that QEMU on arm64 emits neither of these paths itself: the linuxboot option ROM path is what
+// x86 uses for -kernel, and /fw-cfg/<name> is our own convention for named fw_cfg files.
+fn source_from_of_path(path: &str) -> Option<&str> {
+    if path.starts_with("/rom@genroms/linuxboot") {
+        return Some(CFG_BOOT_SOURCE_KERNEL);
+    }
+    path.strip_prefix("/fw-cfg/")
+        .filter(|name| !name.is_empty())
+}
+
+/// Return the boot sources in the order in which they should be tried. Sources that appear in
+/// QEMU's bootorder file (which is derived from the bootindex= device properties) come first.
+/// The remaining default sources are tried next, unless strict boot ordering is in effect and
+/// bootorder referred to at least one source we support.
+pub fn boot_sources(fwcfg: &FwCfg, defaults: &[&str]) -> Vec<String> {
+    let mut sources: Vec<String> = Vec::new();
+    let mut strict = false;
+
+    if let Some(v) = fwcfg.read_file("bootorder") {
+        for path in from_utf8(&v)
+            .unwrap_or_default()
+            .split(['\n', '\0'])
+            .map(str::trim)
+            .filter(|p| !p.is_empty())
+        {
+            // QEMU appends HALT if -boot strict=on was given. Only honour it if any of the
+            // devices was recognized, or we would end up with nothing to boot at all.
+            if path == "HALT" {
+                strict = !sources.is_empty();
+                break;
+            }
+            match source_from_of_path(path) {
+                Some(s) => {
+                    info!("bootorder: {} -> {}\n", path, s);
+                    if !sources.iter().any(|t| t == s) {
+                        sources.push(s.to_string());
+                    }
+                }
+                None => info!("bootorder: ignoring unsupported boot device {}\n", path),
+            }
+        }
+    }
+
+    if !strict {
+        for s in defaults {
+            if !sources.iter().any(|t| t == s) {
+                sources.push(s.to_string());
+            }
+        }
+    }
+    info!("Boot order: {:?}\n", sources);
+    sources
+}
+
+/// Handle the failure to boot from any of the boot sources. If QEMU was started with
+/// -boot reboot-timeout=<ms>, wait for the given time and reboot. Otherwise, return to the
+/// caller so the system will be powered off.
+pub fn handle_boot_failure(fwcfg: &FwCfg) {
+    let wait = fwcfg
+        .read_file("etc/boot-fail-wait")
+        .and_then(|v| Some(u32::from_le_bytes(v.get(..4)?.try_into().ok()?)));
+
+    match wait {
+        // A value of -1 means 'never reboot'
+        Some(ms) if ms != u32::MAX => {
+            error!("Boot failed, rebooting in {} ms\n", ms);
+            timer::delay_ms(ms);
+            psci::reboot();
+        }
+        _ => error!("Boot failed, powering off\n"),
+    }
+}
@@ -0,0 +1,129 @@
+// SPDX-License-Identifier: GPL-2.0
+// Copyright 2026 Google LLC
+
+//! Runtime configuration supplied by the host via the opt/org.efilite/config fw_cfg file,
+//! which consists of key=value pairs, one per line. Empty lines and lines starting with '#'
+//! are ignored.
+//!
+//! Supported keys:
+//!   loglevel = <level>[,<module>=<level>..]  see logfilter.rs, e.g., info,fwcfg=trace
+//!   acpi = on | off               use ACPI tables if provided by the host (default: on)
+//!   bs-pool-size = <size>         size of the EfiBootServicesData pool
+//!   rt-pool-size = <size>         size of the EfiRuntimeServicesData pool
+//!   boot-sources = <src>,<src>..  fw_cfg files to boot from, or 'kernel' for -kernel
+//!
+//! Sizes may be given in decimal or hex, with an optional K or M suffix.
+
+use crate::fwcfg::FwCfg;
+use crate::logfilter::LogFilter;
+
+use alloc::string::*;
+use alloc::vec::Vec;
+use core::str::from_utf8;
+use log::warn;
+
+const CONFIG_FILE: &str = "opt/org.efilite/config";
+
+#[derive(Default)]
+pub struct Config {
+    pub log_filter: Option<LogFilter>,
+    pub acpi: Option<bool>,
+    pub bs_pool_size: Option<usize>,
+    pub rt_pool_size: Option<usize>,
+    pub boot_sources: Option<Vec<String>>,
+}
+
+fn parse_size(s: &str) -> Option<usize> {
+    let (s, shift) = match s.as_bytes().last()? {
+        b'k' | b'K' => (&s[..s.len() - 1], 10),
+        b'm' | b'M' => (&s[..s.len() - 1], 20),
+        _ => (s, 0),
+    };
+    let val = match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok()?,
+        None => s.parse().ok()?,
+    };
+    val.checked_mul(1 << shift)
+}
+
+fn parse_bool(s: &str) -> Option<bool> {
+    match s {
+        "on" | "yes" | "true" | "1" => Some(true),
+        "off" | "no" | "false" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+impl Config {
+    fn parse(s: &str) -> Config {
+        let mut config = Config::default();
+
+        for line in s.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, val)) = line.split_once('=') else {
+                warn!("Ignoring malformed config line {:?}\n", line);
+                continue;
+            };
+            let (key, val) = (key.trim(), val.trim());
+            let ok = match key {
+                "loglevel" => {
+                    config.log_filter = LogFilter::parse(val);
+                    config.log_filter.is_some()
+                }
+                "acpi" => {
+                    config.acpi = parse_bool(val);
+                    config.acpi.is_some()
+                }
+                "bs-pool-size" => {
+                    config.bs_pool_size = parse_size(val);
+                    config.bs_pool_size.is_some()
+                }
+                "rt-pool-size" => {
+                    config.rt_pool_size = parse_size(val);
+                    config.rt_pool_size.is_some()
+                }
+                "boot-sources" => {
+                    let v: Vec<String> = val
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|s| !s.is_empty())
+                        .map(String::from)
+                        .collect();
+                    config.boot_sources = Some(v);
+                    true
+                }
+                _ => {
+                    warn!("Ignoring unknown config key {:?}\n", key);
+                    continue;
+                }
+            };
+            if !ok {
+                warn!(
+                    "Ignoring invalid value {:?} for config key {:?}\n",
+                    val, key
+                );
+            }
+        }
+        config
+    }
+
+    /// Read the configuration from fw_cfg, or return the default configuration if the host
+    /// did not provide one
+    pub fn from_fwcfg(fwcfg: &FwCfg) -> Config {
+        let Some(v) = fwcfg.read_file(CONFIG_FILE) else {
+            return Config::default();
+        };
+        match from_utf8(&v) {
+            Ok(s) => {
+                log::info!("Using configuration from {}\n", CONFIG_FILE);
+                Self::parse(s.trim_end_matches('\0'))
+            }
+            Err(_) => {
+                warn!("Ignoring {} - not valid UTF-8\n", CONFIG_FILE);
+                Config::default()
+            }
+        }
+    }
+}
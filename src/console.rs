@@ -2,85 +2,330 @@
 // Copyright 2022-2023 Google LLC
 // Author: Ard Biesheuvel <ardb@google.com>
 
+use crate::virtio::VirtioConsole;
+
 use core::cell::RefCell;
 use core::fmt::Write;
 use core::ops::Range;
+use core::ptr::addr_of;
 use fdt::node::FdtNode;
+use fdt::Fdt;
 use log::{Metadata, Record};
 use mmio::{Allow, Deny, VolBox};
 use once_cell::unsync::OnceCell;
 
-struct DumbSerialConsoleWriter(VolBox<u32, Deny, Allow>);
+const UARTDR: usize = 0x00;
+const UARTFR: usize = 0x18;
+const UARTIBRD: usize = 0x24;
+const UARTFBRD: usize = 0x28;
+const UARTLCR_H: usize = 0x2c;
+const UARTCR: usize = 0x30;
 
-impl Write for DumbSerialConsoleWriter {
-    fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        let out = &mut self.0;
+const UARTFR_BUSY: u32 = 1 << 3;
+const UARTFR_RXFE: u32 = 1 << 4;
+const UARTFR_TXFF: u32 = 1 << 5;
+
+const UARTLCR_H_FEN: u32 = 1 << 4;
+const UARTLCR_H_WLEN_8: u32 = 3 << 5;
+
+const UARTCR_UARTEN: u32 = 1 << 0;
+const UARTCR_TXE: u32 = 1 << 8;
+const UARTCR_RXE: u32 = 1 << 9;
+
+const UART_RBR: usize = 0;
+const UART_THR: usize = 0;
+const UART_LSR: usize = 5;
+
+const UART_LSR_DR: u32 = 1 << 0;
+const UART_LSR_THRE: u32 = 1 << 5;
+const UART_LSR_TEMT: u32 = 1 << 6;
+
+struct Pl011 {
+    dr: VolBox<u32, Allow, Allow>,
+    fr: VolBox<u32, Allow, Deny>,
+    ibrd: VolBox<u32, Deny, Allow>,
+    fbrd: VolBox<u32, Deny, Allow>,
+    lcr_h: VolBox<u32, Deny, Allow>,
+    cr: VolBox<u32, Allow, Allow>,
+
+    // In dumb mode, we just write to the data register without checking the flag register,
+    // which is sufficient for emulated UARTs that never apply back pressure
+    dumb: bool,
+}
+
+impl Pl011 {
+    // SAFETY: base must point to the MMIO registers of a PL011 compatible UART
+    unsafe fn new(base: usize, dumb: bool) -> Self {
+        let reg = |offset: usize| (base + offset) as *mut u32;
+        Pl011 {
+            dr: VolBox::new(reg(UARTDR)),
+            fr: VolBox::new(reg(UARTFR)),
+            ibrd: VolBox::new(reg(UARTIBRD)),
+            fbrd: VolBox::new(reg(UARTFBRD)),
+            lcr_h: VolBox::new(reg(UARTLCR_H)),
+            cr: VolBox::new(reg(UARTCR)),
+            dumb,
+        }
+    }
+
+    fn wait_idle(&mut self) {
+        while !self.dumb && self.fr.read() & UARTFR_BUSY != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    // Program the UART for 8n1 at the given baud rate, with the FIFOs enabled
+    fn configure(&mut self, clock: u64, baud: u64) {
+        // The baud rate divisor is a fixed point value with 6 fractional bits
+        let div = (clock * 4 + baud / 2) / baud;
+        if !(1 << 6..1 << 22).contains(&div) {
+            log::warn!("Unsupported PL011 baud rate {} @ {} Hz\n", baud, clock);
+            return;
+        }
+
+        self.wait_idle();
+        self.cr.write(0);
+        self.ibrd.write((div >> 6) as u32);
+        self.fbrd.write((div & 0x3f) as u32);
+        // LCR_H must be written after IBRD/FBRD for the divisor to take effect
+        self.lcr_h.write(UARTLCR_H_WLEN_8 | UARTLCR_H_FEN);
+        self.cr.write(UARTCR_UARTEN | UARTCR_TXE | UARTCR_RXE);
+    }
+
+    fn write_byte(&mut self, b: u8) {
+        while !self.dumb && self.fr.read() & UARTFR_TXFF != 0 {
+            core::hint::spin_loop();
+        }
+        self.dr.write(b as u32);
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        if self.fr.read() & UARTFR_RXFE != 0 {
+            return None;
+        }
+        Some(self.dr.read() as u8)
+    }
+}
+
+// A 16550 compatible UART, whose registers may be spaced and sized differently depending on how
+// it is wired up, as described by the reg-shift and reg-io-width DT properties
+struct Ns16550 {
+    base: usize,
+    reg_shift: usize,
+    reg_io_width: usize,
+}
+
+impl Ns16550 {
+    fn reg(&self, index: usize) -> usize {
+        self.base + (index << self.reg_shift)
+    }
+
+    fn read(&mut self, index: usize) -> u32 {
+        let addr = self.reg(index);
+        // SAFETY: base points to the MMIO registers of a 16550 compatible UART
+        unsafe {
+            match self.reg_io_width {
+                4 => (addr as *const u32).read_volatile(),
+                _ => (addr as *const u8).read_volatile() as u32,
+            }
+        }
+    }
+
+    fn write(&mut self, index: usize, val: u32) {
+        let addr = self.reg(index);
+        // SAFETY: base points to the MMIO registers of a 16550 compatible UART
+        unsafe {
+            match self.reg_io_width {
+                4 => (addr as *mut u32).write_volatile(val),
+                _ => (addr as *mut u8).write_volatile(val as u8),
+            }
+        }
+    }
 
+    fn wait_idle(&mut self) {
+        while self.read(UART_LSR) & UART_LSR_TEMT == 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn write_byte(&mut self, b: u8) {
+        while self.read(UART_LSR) & UART_LSR_THRE == 0 {
+            core::hint::spin_loop();
+        }
+        self.write(UART_THR, b as u32);
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        if self.read(UART_LSR) & UART_LSR_DR == 0 {
+            return None;
+        }
+        Some(self.read(UART_RBR) as u8)
+    }
+}
+
+enum Device {
+    Pl011(Pl011),
+    Ns16550(Ns16550),
+    Virtio(VirtioConsole),
+}
+
+impl Device {
+    fn wait_idle(&mut self) {
+        match self {
+            Device::Pl011(u) => u.wait_idle(),
+            Device::Ns16550(u) => u.wait_idle(),
+            Device::Virtio(v) => v.flush(),
+        }
+    }
+
+    fn write_byte(&mut self, b: u8) {
+        match self {
+            Device::Pl011(u) => u.write_byte(b),
+            Device::Ns16550(u) => u.write_byte(b),
+            Device::Virtio(v) => v.write_byte(b),
+        }
+    }
+
+    // Submit any buffered output - only the virtio console buffers output
+    fn flush(&mut self) {
+        if let Device::Virtio(v) = self {
+            v.flush();
+        }
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        match self {
+            Device::Pl011(u) => u.read_byte(),
+            Device::Ns16550(u) => u.read_byte(),
+            Device::Virtio(v) => v.read_byte(),
+        }
+    }
+}
+
+impl Write for Device {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
         for b in s.as_bytes().iter() {
             if *b == b'\n' {
-                out.write(b'\r' as u32);
+                self.write_byte(b'\r');
             }
-            out.write(*b as u32)
+            self.write_byte(*b);
         }
         Ok(())
     }
 }
 
-pub struct DumbSerialConsole {
+pub struct SerialConsole {
     pub base: usize,
-    out: RefCell<DumbSerialConsoleWriter>,
+    dev: RefCell<Device>,
 }
 
-// SAFETY: DumbSerialConsole is only accessible via shared references, and its interior mutability
+// SAFETY: SerialConsole is only accessible via shared references, and its interior mutability
 // is implemented using a RefCell. EFI boot services are single threaded, and the only way we might
 // enter recursively is when a panic is triggered by a write to the log, which is why the log::Log
 // implementation uses try_borrow_mut().
-unsafe impl Sync for DumbSerialConsole {}
+unsafe impl Sync for SerialConsole {}
 
-pub fn init(base: &Range<usize>) -> &'static DumbSerialConsole {
+fn init(base: &Range<usize>, dev: Device) -> &'static SerialConsole {
     // Statically allocated so we can init the console before the heap
-    static mut CON: OnceCell<DumbSerialConsole> = OnceCell::new();
+    static mut CON: OnceCell<SerialConsole> = OnceCell::new();
 
     // SAFETY: the code is single threaded and does not recurse, so the first invocation will
     // run to completion before this code is ever executed again.
     unsafe {
-        let v = VolBox::<u32, Deny, Allow>::new(base.start as *mut u32);
-        CON.get_or_init(|| DumbSerialConsole {
+        (*addr_of!(CON)).get_or_init(|| SerialConsole {
             base: base.start,
-            out: RefCell::new(DumbSerialConsoleWriter(v)),
+            dev: RefCell::new(dev),
         })
     }
 }
 
-pub fn init_from_fdt_node(node: FdtNode) -> Option<&'static DumbSerialConsole> {
-    let reg = node.reg()?.nth(0)?;
+// Take the UART's input clock frequency from the node itself, or from the first clock it refers to
+fn clock_frequency(fdt: &Fdt, node: &FdtNode) -> Option<u64> {
+    if let Some(f) = node.property("clock-frequency") {
+        return Some(f.as_usize()? as u64);
+    }
+    let clocks = node.property("clocks")?.value;
+    let phandle = u32::from_be_bytes(clocks.get(..4)?.try_into().ok()?);
+    let clk = fdt.find_phandle(phandle)?;
+    Some(clk.property("clock-frequency")?.as_usize()? as u64)
+}
+
+pub fn init_from_fdt_node(fdt: &Fdt, node: FdtNode) -> Option<&'static SerialConsole> {
+    let reg = node.reg()?.next()?;
     let base = reg.starting_address as usize;
     let size = reg.size?;
-    Some(init(&(base..base + size)))
+
+    let is_compatible = |compat: &[&str]| {
+        node.compatible()
+            .is_some_and(|c| c.all().any(|c| compat.contains(&c)))
+    };
+    let prop = |name: &str| node.property(name).and_then(|p| p.as_usize());
+
+    // Drive the UART as a proper PL011 or 16550 if it is described as one, and fall back to
+    // treating it as a PL011 in dumb mode otherwise
+    let dumb = !is_compatible(&["arm,pl011"]);
+    let dev = if is_compatible(&["virtio,mmio"]) {
+        // SAFETY: the DT describes a virtio-mmio transport at this address
+        Device::Virtio(unsafe { VirtioConsole::new(base)? })
+    } else if is_compatible(&["ns16550a", "ns16550", "ns8250"]) {
+        let reg_io_width = prop("reg-io-width").unwrap_or(1);
+        if !matches!(reg_io_width, 1 | 4) {
+            return None;
+        }
+        Device::Ns16550(Ns16550 {
+            base,
+            reg_shift: prop("reg-shift").unwrap_or(0),
+            reg_io_width,
+        })
+    } else {
+        // SAFETY: the DT describes a UART at this address
+        Device::Pl011(unsafe { Pl011::new(base, dumb) })
+    };
+    let con = init(&(base..base + size), dev);
+
+    // Only reprogram the line settings if the DT tells us what they should be
+    let speed = prop("current-speed").filter(|s| *s > 0);
+    if let (Device::Pl011(u), Some(clock), Some(baud)) = (
+        &mut *con.dev.borrow_mut(),
+        clock_frequency(fdt, &node),
+        speed,
+    ) {
+        if !dumb {
+            u.configure(clock, baud as u64);
+        }
+    }
+    Some(con)
 }
 
-impl efiloader::SimpleConsole for DumbSerialConsole {
+impl efiloader::SimpleConsole for SerialConsole {
     fn write_string(&self, s: &str) {
-        self.out.borrow_mut().write_str(s).ok();
+        let mut dev = self.dev.borrow_mut();
+        dev.write_str(s).ok();
+        dev.flush();
     }
 
     fn read_byte(&self) -> Option<u8> {
-        None
+        self.dev.try_borrow_mut().ok()?.read_byte()
     }
 }
 
-impl log::Log for DumbSerialConsole {
+impl log::Log for SerialConsole {
     fn enabled(&self, metadata: &Metadata) -> bool {
         metadata.level() <= log::max_level()
     }
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
-            if let Ok(mut out) = self.out.try_borrow_mut() {
+            if let Ok(mut out) = self.dev.try_borrow_mut() {
                 write!(out, "efilite {} - {}", record.level(), record.args()).ok();
+                out.flush();
             }
         }
     }
 
-    fn flush(&self) {}
+    fn flush(&self) {
+        if let Ok(mut dev) = self.dev.try_borrow_mut() {
+            dev.wait_idle();
+        }
+    }
 }
@@ -2,24 +2,51 @@
 // Copyright 2022-2023 Google LLC
 // Author: Ard Biesheuvel <ardb@google.com>
 
-use alloc::{boxed::*, collections::*, vec::*};
+use alloc::{boxed::*, collections::*, string::*, vec::*};
 use core::{cell::*, fmt, marker::*, mem::*, pin::pin, slice, str::from_utf8, sync::atomic::*};
 use efiloader::*;
 use efiloader::{memmap::*, memorytype::*};
 use mmio::*;
 
+use crate::sha256::{Sha256, SHA256_DIGEST_SIZE};
+use crate::{smbios, timer};
+
 struct FwCfgMmio {
     // read-only data register
     data: VolBox<u64, Allow, Deny>,
 
+    // the same data register, for accessing it one byte at a time
+    data_byte: VolBox<u8, Allow, Deny>,
+
     // write-only selector register
     selector: VolBox<u16, Deny, Allow>,
 
     // write-only DMA register
     dmacontrol: VolBox<u64, Deny, Allow>,
+
+    // whether the DMA interface is implemented
+    dma: bool,
+
+    // the currently selected item and the offset of the next access into it, if known
+    cursor: Option<(u16, usize)>,
+
+    stats: FwCfgStats,
+}
+
+#[derive(Default)]
+struct FwCfgStats {
+    selects: usize,
+    skipped: usize,
+    transferred: usize,
+    ticks: u64,
 }
 
-pub struct FwCfg(RefCell<FwCfgMmio>);
+pub struct FwCfg {
+    mmio: RefCell<FwCfgMmio>,
+
+    // the file directory, indexed by name, read on first use
+    files: OnceCell<BTreeMap<String, FwCfgFile>>,
+}
 
 // SAFETY: EFI boot services are single threaded, and FwCfg uses RefCells for interior mutability,
 // which ensures that mutable references taken from the same thread will cause a panic. Such
@@ -27,18 +54,51 @@ pub struct FwCfg(RefCell<FwCfgMmio>);
 // of it.
 unsafe impl Sync for FwCfg {}
 
+const CFG_SIGNATURE: u16 = 0x00;
+const CFG_ID: u16 = 0x01;
+
+const CFG_SIGNATURE_QEMU: [u8; 4] = *b"QEMU";
+
+const CFG_FEATURE_TRADITIONAL: u32 = 1 << 0;
+const CFG_FEATURE_DMA: u32 = 1 << 1;
+
 const CFG_KERNEL_SIZE: u16 = 0x08;
 const CFG_KERNEL_DATA: u16 = 0x11;
 
 const CFG_INITRD_SIZE: u16 = 0x0b;
 const CFG_INITRD_DATA: u16 = 0x12;
 
+const CFG_CMDLINE_SIZE: u16 = 0x14;
+const CFG_CMDLINE_DATA: u16 = 0x15;
+
 const CFG_FILE_DIR: u16 = 0x19;
 
+const CFG_CMDLINE_APPEND_FILE: &str = "opt/org.efilite/cmdline-append";
+const CFG_INITRD_DIR: &str = "opt/org.efilite/initrd/";
+const CFG_KERNEL_DIGEST_FILE: &str = "opt/org.efilite/kernel.sha256";
+const CFG_INITRD_DIGEST_FILE: &str = "opt/org.efilite/initrd.sha256";
+
+pub const CFG_BOOT_SOURCE_KERNEL: &str = "kernel";
+
+const CFG_LOADER_ALLOC_ZONE_HIGH: u8 = 1;
+const CFG_LOADER_ALLOC_ZONE_FSEG: u8 = 2;
+
+// There is no F-segment on arm64, and even HIGH blobs may be referenced by 32-bit
+// pointers (e.g., the VM generation ID in the AML), so keep all blobs below 4 GiB
+// like EDK2 does.
+const CFG_LOADER_ALLOC_LIMIT: u64 = u32::MAX as u64;
+
 const CFG_DMACTL_DONE: u32 = 0;
 const CFG_DMACTL_ERROR: u32 = 1;
 const CFG_DMACTL_READ: u32 = 2;
 const CFG_DMACTL_SKIP: u32 = 4;
+const CFG_DMACTL_SELECT: u32 = 8;
+const CFG_DMACTL_WRITE: u32 = 16;
+
+const CFG_DMA_MAX_CHUNK: usize = 1 << 30;
+
+// Size of the buffer used to read the file contents for hashing
+const CFG_DIGEST_BOUNCE_SIZE: usize = 64 * 1024;
 
 #[repr(C)]
 struct DmaTransfer {
@@ -52,14 +112,32 @@ type FwCfgFilename = [u8; 56];
 
 #[derive(Copy, Clone)]
 #[repr(C)]
-struct FwCfgFile {
+pub struct FwCfgFile {
     _size: u32,
     _select: u16,
     reserved: u16,
     filename: FwCfgFilename,
 }
 
+fn filename_str(filename: &FwCfgFilename) -> Option<&str> {
+    from_utf8(filename).ok().map(|s| s.trim_end_matches("\0"))
+}
+
+// Strings passed via fw_cfg are typically NUL terminated, and files created
+// from the command line may carry a trailing newline
+fn trimmed_string(v: &[u8]) -> Option<String> {
+    let s = from_utf8(v).ok()?.trim_end_matches('\0').trim();
+    if s.is_empty() {
+        return None;
+    }
+    Some(s.to_string())
+}
+
 impl FwCfgFile {
+    pub fn name(&self) -> Option<&str> {
+        filename_str(&self.filename)
+    }
+
     pub fn size(&self) -> usize {
         u32::to_be(self._size) as usize
     }
@@ -83,15 +161,16 @@ impl<'a, T> FwCfgFileIterator<'a, T> {
         let len = self.count as usize;
         let mut v = Vec::<T>::with_capacity(len);
         let size = len * size_of::<T>();
-        let mut mmio = self.fwcfg.0.borrow_mut();
-
-        mmio.selector.write(u16::to_be(self.select));
-        fence(Ordering::Release);
-
-        if self.offset > 0 {
-            mmio.dma_transfer(0, self.offset as u32).ok()?;
-        }
-        mmio.dma_transfer(v.as_mut_ptr() as u64, size as u32).ok()?;
+        let mut mmio = self.fwcfg.mmio.borrow_mut();
+
+        mmio.transfer(
+            self.select,
+            self.offset as usize,
+            CFG_DMACTL_READ,
+            v.as_mut_ptr() as u64,
+            size,
+        )
+        .ok()?;
         unsafe {
             v.set_len(len);
         }
@@ -118,16 +197,16 @@ impl<T: Copy> Iterator for FwCfgFileIterator<'_, T> {
             item: MaybeUninit::<T>::uninit(),
             _pin: PhantomPinned
         });
-        let mut mmio = self.fwcfg.0.borrow_mut();
-
-        mmio.selector.write(u16::to_be(self.select));
-        fence(Ordering::Release);
-
-        if offset > 0 {
-            mmio.dma_transfer(0, offset as u32).ok()?;
-        }
-        mmio.dma_transfer(&out.item as *const _ as u64, itemsz as u32)
-            .ok()?;
+        let mut mmio = self.fwcfg.mmio.borrow_mut();
+
+        mmio.transfer(
+            self.select,
+            offset as usize,
+            CFG_DMACTL_READ,
+            &out.item as *const _ as u64,
+            itemsz as usize,
+        )
+        .ok()?;
         self.next += 1;
 
         unsafe { Some(out.item.assume_init()) }
@@ -139,18 +218,142 @@ impl FwCfgMmio {
         unsafe {
             FwCfgMmio {
                 data: VolBox::<u64, Allow, Deny>::new(addr as *mut u64),
+                data_byte: VolBox::<u8, Allow, Deny>::new(addr as *mut u8),
                 selector: VolBox::<u16, Deny, Allow>::new(addr.offset(8) as *mut u16),
                 dmacontrol: VolBox::<u64, Deny, Allow>::new(addr.offset(16) as *mut u64),
+                dma: false,
+                cursor: None,
+                stats: FwCfgStats::default(),
             }
         }
     }
 
-    fn dma_transfer(&mut self, addr: u64, size: u32) -> Result<(), ()> {
-        let control = match addr {
-            0 => CFG_DMACTL_SKIP,
-            _ => CFG_DMACTL_READ,
+    // Check the signature and return the feature bitmap, which tells us whether DMA is supported
+    fn probe(&mut self) -> Option<u32> {
+        let mut sig = [0u8; 4];
+        self.select(CFG_SIGNATURE);
+        self.pio_transfer(sig.as_mut_ptr(), sig.len());
+        if sig != CFG_SIGNATURE_QEMU {
+            return None;
+        }
+
+        let mut features = [0u8; 4];
+        self.select(CFG_ID);
+        self.pio_transfer(features.as_mut_ptr(), features.len());
+        Some(u32::from_le_bytes(features))
+    }
+
+    // Select an item via the selector register. The cursor is invalidated, as the caller
+    // may access the data register directly.
+    fn select(&mut self, item: u16) {
+        self.selector.write(u16::to_be(item));
+        fence(Ordering::Release);
+        self.cursor = None;
+        self.stats.selects += 1;
+    }
+
+    // Read from (or write to) item at offset. If the item is still selected from a previous
+    // access, and the offset is not below the current position, we only skip ahead as needed.
+    // Otherwise, the item is selected and the transfer starts from the beginning.
+    fn transfer(
+        &mut self,
+        item: u16,
+        offset: usize,
+        control: u32,
+        addr: u64,
+        size: usize,
+    ) -> Result<(), ()> {
+        let (select, skip) = match self.cursor.take() {
+            Some((i, pos)) if i == item && pos <= offset => (None, offset - pos),
+            _ => (Some(item), offset),
         };
+        let start = timer::counter();
+
+        if self.dma {
+            // Issue the selection as part of the first DMA descriptor
+            let select = if skip > 0 {
+                self.dma_chunked(select, CFG_DMACTL_SKIP, 0, skip)?;
+                None
+            } else {
+                select
+            };
+            self.dma_chunked(select, control, addr, size)?;
+        } else {
+            // Writes via the data register are not supported by QEMU
+            if control != CFG_DMACTL_READ {
+                return Err(());
+            }
+            if let Some(item) = select {
+                self.select(item);
+            }
+            self.pio_transfer(core::ptr::null_mut(), skip);
+            self.pio_transfer(addr as *mut u8, size);
+        }
 
+        self.stats.skipped += skip;
+        self.stats.transferred += size;
+        self.stats.ticks += timer::counter() - start;
+        self.cursor = Some((item, offset + size));
+        Ok(())
+    }
+
+    // Read (or skip if ptr is NULL) size bytes via the data register. The register is
+    // big-endian, so wider reads return the bytes in the order they appear in the item.
+    fn pio_transfer(&mut self, ptr: *mut u8, size: usize) {
+        let mut p = ptr;
+        let mut remaining = size;
+        while remaining >= size_of::<u64>() {
+            let v = self.data.read();
+            if !p.is_null() {
+                unsafe {
+                    (p as *mut u64).write_unaligned(v);
+                    p = p.add(size_of::<u64>());
+                }
+            }
+            remaining -= size_of::<u64>();
+        }
+        while remaining > 0 {
+            let b = self.data_byte.read();
+            if !p.is_null() {
+                unsafe {
+                    p.write(b);
+                    p = p.add(1);
+                }
+            }
+            remaining -= 1;
+        }
+    }
+
+    // The DMA descriptor has a 32-bit length field, so split up larger transfers. A selection
+    // is always issued, even if there is no data to transfer.
+    fn dma_chunked(
+        &mut self,
+        mut select: Option<u16>,
+        control: u32,
+        addr: u64,
+        size: usize,
+    ) -> Result<(), ()> {
+        let mut done = 0;
+        while done < size || select.is_some() {
+            let len = (size - done).min(CFG_DMA_MAX_CHUNK);
+            let a = match addr {
+                0 => 0, // skip
+                _ => addr + done as u64,
+            };
+            let control = match select.take() {
+                Some(item) => {
+                    self.stats.selects += 1;
+                    control | CFG_DMACTL_SELECT | (item as u32) << 16
+                }
+                None => control,
+            };
+            self.dma_command(control, a, len as u32)?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn dma_command(&mut self, control: u32, addr: u64, size: u32) -> Result<(), ()> {
         let xfer = pin!(DmaTransfer {
             control: u32::to_be(control),
             length: u32::to_be(size),
@@ -158,6 +361,8 @@ impl FwCfgMmio {
             pin: PhantomPinned,
         });
 
+        // Make sure the descriptor and any data to be written are visible to the device
+        fence(Ordering::Release);
         self.dmacontrol.write(u64::to_be(&*xfer as *const _ as u64));
         fence(Ordering::Release);
 
@@ -228,54 +433,102 @@ pub enum FwCfgLoaderCmdType {
 
 #[repr(C)]
 pub struct FwCfgLoaderEntry {
-    pub _type: FwCfgLoaderCmdType,
+    // Kept as a raw u32 rather than a FwCfgLoaderCmdType, as the host may
+    // put any value here, and only valid discriminants may be stored in an enum
+    pub _type: u32,
     pub u: FwCfgLoaderUnion,
 }
 
+impl FwCfgLoaderEntry {
+    pub fn command(&self) -> Option<FwCfgLoaderCmdType> {
+        match self._type {
+            1 => Some(FwCfgLoaderCmdType::FwCfgLoaderCmdAllocate),
+            2 => Some(FwCfgLoaderCmdType::FwCfgLoaderCmdAddPointer),
+            3 => Some(FwCfgLoaderCmdType::FwCfgLoaderCmdAddChecksum),
+            4 => Some(FwCfgLoaderCmdType::FwCfgLoaderCmdWritePointer),
+            _ => None,
+        }
+    }
+}
+
 impl fmt::Debug for FwCfgLoaderEntry {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut out = f.debug_struct("FwCfgLoaderEntry");
 
-        match self._type {
-            FwCfgLoaderCmdType::FwCfgLoaderCmdAllocate => unsafe {
+        match self.command() {
+            Some(FwCfgLoaderCmdType::FwCfgLoaderCmdAllocate) => unsafe {
                 out.field("u", &self.u.allocate);
             },
-            FwCfgLoaderCmdType::FwCfgLoaderCmdAddPointer => unsafe {
+            Some(FwCfgLoaderCmdType::FwCfgLoaderCmdAddPointer) => unsafe {
                 out.field("u", &self.u.add_pointer);
             },
-            FwCfgLoaderCmdType::FwCfgLoaderCmdAddChecksum => unsafe {
+            Some(FwCfgLoaderCmdType::FwCfgLoaderCmdAddChecksum) => unsafe {
                 out.field("u", &self.u.add_checksum);
             },
-            FwCfgLoaderCmdType::FwCfgLoaderCmdWritePointer => unsafe {
+            Some(FwCfgLoaderCmdType::FwCfgLoaderCmdWritePointer) => unsafe {
                 out.field("u", &self.u.write_pointer);
             },
-            _ => (),
+            _ => {
+                out.field("_type", &self._type);
+            }
         };
         out.finish()
     }
 }
 
 impl FwCfg {
-    fn attach(addr: *const u8) -> &'static FwCfg {
-        Box::leak(Box::new(FwCfg(RefCell::new(FwCfgMmio::new(addr)))))
+    fn attach(addr: *const u8) -> Option<&'static FwCfg> {
+        let mut mmio = FwCfgMmio::new(addr);
+        let features = mmio.probe().or_else(|| {
+            log::warn!("fwcfg signature mismatch\n");
+            None
+        })?;
+        if features & CFG_FEATURE_TRADITIONAL == 0 {
+            log::warn!("fwcfg does not implement the traditional interface\n");
+            return None;
+        }
+        mmio.dma = features & CFG_FEATURE_DMA != 0;
+        if !mmio.dma {
+            log::info!("fwcfg DMA not supported, using data register accesses\n");
+        }
+        Some(Box::leak(Box::new(FwCfg {
+            mmio: RefCell::new(mmio),
+            files: OnceCell::new(),
+        })))
     }
 
-    fn files(&self) -> FwCfgFileIterator<FwCfgFile> {
-        let size = u32::to_be(self.get_file_size(CFG_FILE_DIR) as u32);
-        FwCfgFileIterator {
-            count: size,
-            next: 0,
-            select: CFG_FILE_DIR,
-            offset: size_of::<u32>() as u32,
-            fwcfg: self,
-            phantom: PhantomData,
-        }
+    /// Return the fw_cfg file directory, indexed by name
+    pub fn files(&self) -> &BTreeMap<String, FwCfgFile> {
+        self.files.get_or_init(|| {
+            let count = u32::to_be(self.get_file_size(CFG_FILE_DIR) as u32);
+            let files = FwCfgFileIterator::<FwCfgFile> {
+                count,
+                next: 0,
+                select: CFG_FILE_DIR,
+                offset: size_of::<u32>() as u32,
+                fwcfg: self,
+                phantom: PhantomData,
+            }
+            .to_vec()
+            .unwrap_or_else(|| {
+                log::warn!("Failed to read fwcfg file directory\n");
+                Vec::new()
+            });
+            files
+                .into_iter()
+                .filter_map(|f| Some((f.name()?.to_string(), f)))
+                .collect()
+        })
+    }
+
+    /// Look up a file in the fw_cfg file directory by name. The directory is read from the host
+    /// only once, and cached for subsequent lookups.
+    pub fn find_file(&self, name: &str) -> Option<FwCfgFile> {
+        self.files().get(name).copied()
     }
 
     fn loader(&self) -> Option<Vec<FwCfgLoaderEntry>> {
-        let f = self.files().find(|f| {
-            from_utf8(&f.filename).map_or(false, |s| s.trim_end_matches("\0") == "etc/table-loader")
-        })?;
+        let f = self.find_file("etc/table-loader")?;
         FwCfgFileIterator {
             count: (f.size() / size_of::<FwCfgLoaderEntry>()) as u32,
             next: 0,
@@ -289,7 +542,7 @@ impl FwCfg {
 
     pub fn from_fdt_node(node: fdt::node::FdtNode) -> Option<&'static FwCfg> {
         let addr = node.reg()?.nth(0)?.starting_address;
-        Some(Self::attach(addr))
+        Self::attach(addr)
     }
 
     fn dma_read<T: Copy>(
@@ -299,41 +552,56 @@ impl FwCfg {
         size: usize,
         config_item: u16,
     ) -> Result<(), ()> {
-        let mut mmio = self.0.borrow_mut();
-        mmio.selector.write(u16::to_be(config_item));
-        fence(Ordering::Release);
+        self.mmio.borrow_mut().transfer(
+            config_item,
+            offset,
+            CFG_DMACTL_READ,
+            loadbuffer.as_ptr() as u64,
+            size,
+        )
+    }
 
-        if offset > 0 {
-            mmio.dma_transfer(0, offset as u32).or(Err(()))?;
+    fn dma_write(&self, data: &[u8], offset: usize, config_item: u16) -> Result<(), ()> {
+        self.mmio.borrow_mut().transfer(
+            config_item,
+            offset,
+            CFG_DMACTL_WRITE,
+            data.as_ptr() as u64,
+            data.len(),
+        )
+    }
+
+    /// Write `data` into the named fw_cfg file starting at `offset`. Only files that the host has
+    /// marked as writable can be updated this way, and the write must not extend past the end of
+    /// the file.
+    pub fn write_file(&self, name: &str, offset: usize, data: &[u8]) -> Result<(), &'static str> {
+        let f = self.find_file(name).ok_or("fwcfg file not found")?;
+        let end = offset.checked_add(data.len()).ok_or("Write out of range")?;
+        if end > f.size() {
+            return Err("Write out of range");
+        }
+        if data.is_empty() {
+            return Ok(());
         }
-        mmio.dma_transfer(loadbuffer.as_ptr() as u64, size as u32)
+        self.dma_write(data, offset, f.select())
+            .or(Err("DMA write failed"))
     }
 
     fn get_file_size(&self, size_cfg: u16) -> usize {
-        let mut mmio = self.0.borrow_mut();
-        mmio.selector.write(u16::to_be(size_cfg));
-        fence(Ordering::Release);
+        let mut mmio = self.mmio.borrow_mut();
+        mmio.select(size_cfg);
         mmio.data.read() as usize
     }
 
-    fn load_file<'a, T: Copy>(
-        &self,
-        loadbuffer: &'a mut [MaybeUninit<T>],
-        offset: usize,
-        size: usize,
-        data_cfg: u16,
-    ) -> Result<&'a [T], ()> {
-        let size = size.min(loadbuffer.len() / size_of::<T>());
-        self.dma_read(loadbuffer, offset, size, data_cfg)?;
-        if size < loadbuffer.len() {
-            loadbuffer.split_at_mut(size).1.fill(MaybeUninit::zeroed());
-        }
-        unsafe {
-            Ok(slice::from_raw_parts(
-                loadbuffer.as_ptr() as *const _,
-                loadbuffer.len(),
-            ))
-        }
+    pub fn log_stats(&self) {
+        let mmio = self.mmio.borrow();
+        log::debug!(
+            "fwcfg: {} selects, {} bytes transferred, {} bytes skipped, {} us\n",
+            mmio.stats.selects,
+            mmio.stats.transferred,
+            mmio.stats.skipped,
+            timer::ticks_to_us(mmio.stats.ticks)
+        );
     }
 
     fn load_file_mut<'a>(
@@ -362,7 +630,7 @@ impl FwCfg {
         size_cfg: u16,
         data_cfg: u16,
         preload_bytes: usize,
-    ) -> Option<FwCfgFileLoader> {
+    ) -> Option<FwCfgFileLoader<'_>> {
         let size = self.get_file_size(size_cfg);
         if size == 0 {
             return None;
@@ -370,74 +638,184 @@ impl FwCfg {
         Some(FwCfgFileLoader::new(size, data_cfg, self, preload_bytes))
     }
 
-    pub fn get_kernel_loader(&self) -> Option<FwCfgFileLoader> {
+    fn read_item(&self, size: usize, data_cfg: u16) -> Option<Vec<u8>> {
+        let mut v = Vec::<u8>::with_capacity(size);
+        self.dma_read(v.spare_capacity_mut(), 0, size, data_cfg)
+            .ok()?;
+        unsafe {
+            v.set_len(size);
+        }
+        Some(v)
+    }
+
+    /// Read the entire contents of the named fw_cfg file
+    pub fn read_file(&self, name: &str) -> Option<Vec<u8>> {
+        let f = self.find_file(name)?;
+        self.read_item(f.size(), f.select())
+    }
+
+    /// Return the kernel command line passed via fw_cfg (e.g., using QEMU's -append option)
+    pub fn get_cmdline(&self) -> Option<String> {
+        let size = self.get_file_size(CFG_CMDLINE_SIZE);
+        if size == 0 {
+            return None;
+        }
+        trimmed_string(&self.read_item(size, CFG_CMDLINE_DATA)?)
+    }
+
+    /// Return the arguments that the host wants appended to the command line
+    pub fn get_cmdline_append(&self) -> Option<String> {
+        trimmed_string(&self.read_file(CFG_CMDLINE_APPEND_FILE)?)
+    }
+
+    pub fn get_kernel_loader(&self) -> Option<FwCfgFileLoader<'_>> {
         // Cache the first 1k of the image to ease random access to the PE header
         self.get_loader(CFG_KERNEL_SIZE, CFG_KERNEL_DATA, 1024)
+            .map(|l| l.with_digest(CFG_KERNEL_DIGEST_FILE))
     }
 
-    pub fn get_initrd_loader(&self) -> Option<FwCfgFileLoader> {
-        self.get_loader(CFG_INITRD_SIZE, CFG_INITRD_DATA, 0)
+    /// Return a loader for a boot source, which is either the name of a fw_cfg file, or
+    /// CFG_BOOT_SOURCE_KERNEL to refer to the kernel image passed via QEMU's -kernel option.
+    pub fn get_boot_loader(&self, source: &str) -> Option<FwCfgFileLoader<'_>> {
+        match source {
+            CFG_BOOT_SOURCE_KERNEL => self.get_kernel_loader(),
+            name => FwCfgFileLoader::from_file(name, self, 1024),
+        }
+    }
+
+    /// Return a loader for the initrd, which is the concatenation of the initrd passed via
+    /// QEMU's -initrd option (if any) and all files in the opt/org.efilite/initrd/ directory,
+    /// in name order. Each part is padded to a multiple of 4 bytes, as cpio archives require.
+    pub fn get_initrd_loader(&self) -> Option<FwCfgFileLoader<'_>> {
+        let mut items = Vec::new();
+        let size = self.get_file_size(CFG_INITRD_SIZE);
+        if size > 0 {
+            items.push((CFG_INITRD_DATA, size));
+        }
+        for (name, f) in self.files() {
+            if name.starts_with(CFG_INITRD_DIR) && f.size() > 0 {
+                log::info!("Appending {} to initrd\n", name);
+                items.push((f.select(), f.size()));
+            }
+        }
+        if items.is_empty() {
+            return None;
+        }
+        Some(FwCfgFileLoader::concat(&items, 4, self, 0).with_digest(CFG_INITRD_DIGEST_FILE))
     }
 
     pub fn load_firmware_tables<'a>(&self, efi: &'a EfiContext) -> Result<*const u8, &'static str> {
         FwCfgTableLoader::new(self, efi).load_firmware_tables()
     }
 
-    pub fn load_smbios_tables<'a>(&self, efi: &'a EfiContext) -> Result<*const u8, &'static str> {
-        let v: Vec<_> = self
-            .files()
-            .filter_map(|f| {
-                from_utf8(&f.filename).map_or(None, |s| match s.trim_end_matches("\0") {
-                    "etc/smbios/smbios-anchor" => Some((0, f)),
-                    "etc/smbios/smbios-tables" => Some((1, f)),
-                    _ => None,
-                })
-            })
-            .collect::<BTreeMap<_, _>>()
-            .into_values()
-            .collect();
-        if v.len() < 2 {
+    /// Whether the host provides SMBIOS tables via fw_cfg
+    pub fn has_smbios_tables(&self) -> bool {
+        self.find_file("etc/smbios/smbios-anchor").is_some()
+            && self.find_file("etc/smbios/smbios-tables").is_some()
+    }
+
+    pub fn load_smbios_tables(&self, efi: &EfiContext) -> Result<SmbiosAnchor, &'static str> {
+        let (Some(anchor), Some(tables)) = (
+            self.read_file("etc/smbios/smbios-anchor"),
+            self.read_file("etc/smbios/smbios-tables"),
+        ) else {
             return Err("No SMBIOS tables available");
-        }
+        };
 
-        if v[0].size() < 24 {
+        let smbios3 = if anchor.starts_with(b"_SM3_") && anchor.len() >= SMBIOS3_ANCHOR_SIZE {
+            true
+        } else if anchor.starts_with(b"_SM_")
+            && anchor.len() >= SMBIOS_ANCHOR_SIZE
+            && anchor[16..21] == *b"_DMI_"
+        {
+            false
+        } else {
             return Err("Unexpected anchor type");
+        };
+
+        // Identify ourselves in the BIOS information record
+        let stamped = smbios::stamp_bios_information(&tables);
+        if stamped.is_none() {
+            log::warn!("Failed to update the SMBIOS tables provided by the host\n");
         }
+        let tables = stamped.as_ref().map_or(&tables[..], |(t, _, _)| &t[..]);
+
+        // The 32-bit entry point can only describe tables below 4 GiB
+        let placement = if smbios3 {
+            Placement::Anywhere
+        } else {
+            Placement::Max(u32::MAX as u64)
+        };
 
         let b = efi
             .allocate_pages(
-                memmap::size_to_pages(v[0].size() + v[1].size()),
+                memmap::size_to_pages(anchor.len() + tables.len()),
                 EfiMemoryType::EfiACPIReclaimMemory,
-                Placement::Anywhere,
+                placement,
             )
             .ok_or("Failed to allocate blob memory")?;
 
-        let (a, t) = b.split_at_mut(v[0].size());
-        let a = self.load_file_mut(a, 0, v[0].size(), v[0].select())?;
-        let t = self.load_file_mut(t, 0, v[1].size(), v[1].select())?;
-
-        // SAFETY: a[] is page aligned and at least 24 bytes in size so writing
-        // 8 bytes at offset #16 is safe.
-        unsafe {
-            // Point the address field at offset #16 in the anchor to the table blob
-            let p = a.as_mut_ptr().offset(16) as *mut *const u8;
-            *p = t.as_ptr();
+        let b = &mut b[..anchor.len() + tables.len()];
+        for (d, s) in b.iter_mut().zip(anchor.iter().chain(tables)) {
+            d.write(*s);
         }
+        // SAFETY: all of b[] has been initialized from anchor[] and tables[]
+        let b = unsafe { slice::from_raw_parts_mut(b.as_mut_ptr() as *mut u8, b.len()) };
+        let (a, t) = b.split_at_mut(anchor.len());
+        let addr = t.as_ptr() as u64;
+
+        let checksum = |b: &[u8]| b.iter().fold(0u8, |sum, c| sum.wrapping_sub(*c));
+
+        if smbios3 {
+            // Point the 64-bit address field in the anchor to the table blob
+            a[16..24].copy_from_slice(&addr.to_le_bytes());
+            a[12..16].copy_from_slice(&(t.len() as u32).to_le_bytes());
+
+            let size = (a[6] as usize).clamp(SMBIOS3_ANCHOR_SIZE, a.len());
+            a[5] = 0;
+            a[5] = checksum(&a[..size]);
+
+            Ok(SmbiosAnchor::Smbios3(a.as_ptr()))
+        } else {
+            // Point the 32-bit address field in the intermediate anchor to the table blob
+            let addr = u32::try_from(addr).or(Err("SMBIOS tables allocated above 4 GiB"))?;
+            a[24..28].copy_from_slice(&addr.to_le_bytes());
+
+            let len = u16::try_from(t.len()).or(Err("SMBIOS tables too large"))?;
+            a[22..24].copy_from_slice(&len.to_le_bytes());
+            if let Some((_, count, max)) = stamped {
+                a[8..10].copy_from_slice(&max.to_le_bytes());
+                a[28..30].copy_from_slice(&count.to_le_bytes());
+            }
 
-        let size = a[6] as usize;
-        let mut checksum = 0u8;
+            // The intermediate checksum covers the _DMI_ part of the entry point
+            a[21] = 0;
+            a[21] = checksum(&a[16..SMBIOS_ANCHOR_SIZE]);
 
-        for c in &a[..size] {
-            checksum = checksum.wrapping_sub(*c);
-        }
-        a[5] = checksum;
+            let size = (a[5] as usize).clamp(SMBIOS_ANCHOR_SIZE, a.len());
+            a[4] = 0;
+            a[4] = checksum(&a[..size]);
 
-        Ok(a.as_ptr() as *const u8)
+            Ok(SmbiosAnchor::Smbios(a.as_ptr()))
+        }
     }
 }
 
+const SMBIOS_ANCHOR_SIZE: usize = 31;
+const SMBIOS3_ANCHOR_SIZE: usize = 24;
+
+pub enum SmbiosAnchor {
+    // 32-bit SMBIOS 2.x entry point
+    Smbios(*const u8),
+    // 64-bit SMBIOS 3.x entry point
+    Smbios3(*const u8),
+}
+
 struct FwCfgTableLoader<'a> {
     loaded_tables: BTreeMap<FwCfgFilename, &'a mut [u8]>,
+    // blobs whose addresses are passed back to the host, and which therefore
+    // must not be reclaimed by the OS after it has consumed the ACPI tables
+    nvs_blobs: BTreeSet<FwCfgFilename>,
     fwcfg: &'a FwCfg,
     efi: &'a EfiContext,
 }
@@ -446,59 +824,103 @@ impl<'a> FwCfgTableLoader<'a> {
     pub fn new(fwcfg: &'a FwCfg, efi: &'a EfiContext) -> Self {
         FwCfgTableLoader {
             loaded_tables: BTreeMap::new(),
-            fwcfg: fwcfg,
-            efi: efi,
+            nvs_blobs: BTreeSet::new(),
+            fwcfg,
+            efi,
         }
     }
 
     fn allocate(&mut self, allocate: &FwCfgLoaderAllocate) -> Result<(), &'static str> {
-        let f = self
-            .fwcfg
-            .files()
-            .find(|f| f.filename == allocate.filename)
+        let f = filename_str(&allocate.filename)
+            .and_then(|name| self.fwcfg.find_file(name))
             .ok_or("Failed to locate blob file")?;
+        if f.size() == 0 {
+            return Err("Empty blob file");
+        }
+        if self.loaded_tables.contains_key(&f.filename) {
+            return Err("Blob file allocated more than once");
+        }
+        let memtype = if self.nvs_blobs.contains(&f.filename) {
+            EfiMemoryType::EfiACPIMemoryNVS
+        } else {
+            EfiMemoryType::EfiACPIReclaimMemory
+        };
+        let align = allocate.alignment as u64;
+        if !align.is_power_of_two() {
+            return Err("Invalid blob alignment");
+        }
+        // Page allocations are always page aligned
+        let align = align.max(EFI_PAGE_SIZE as u64);
+        if !matches!(
+            allocate.zone,
+            CFG_LOADER_ALLOC_ZONE_HIGH | CFG_LOADER_ALLOC_ZONE_FSEG
+        ) {
+            return Err("Unsupported blob allocation zone");
+        }
+        let placement = Placement::MaxAlignMask(CFG_LOADER_ALLOC_LIMIT, align - 1);
         let b = self
             .efi
-            .allocate_pages(
-                memmap::size_to_pages(f.size()),
-                EfiMemoryType::EfiACPIReclaimMemory,
-                Placement::Anywhere,
-            )
+            .allocate_pages(memmap::size_to_pages(f.size()), memtype, placement)
             .ok_or("Failed to allocate blob memory")?;
-        let b = self.fwcfg.load_file_mut(b, 0, f.size(), f.select())?;
+
+        let b = self
+            .fwcfg
+            .load_file_mut(&mut b[..f.size()], 0, f.size(), f.select())?;
         self.loaded_tables.insert(f.filename, b);
         Ok(())
     }
 
     fn add_pointer(&mut self, add_pointer: &FwCfgLoaderAddPointer) -> Result<(), &'static str> {
         let tables = &mut self.loaded_tables;
-        let addend = tables
+        let pointee = tables
             .get(&add_pointer.pointee)
-            .ok_or("Unknown pointee blob")?
-            .as_ptr();
+            .ok_or("Unknown pointee blob")?;
+        let (addend, limit) = (pointee.as_ptr() as u64, pointee.len() as u64);
         let pointer = tables
             .get_mut(&add_pointer.pointer)
             .ok_or("Unknown pointer blob")?;
         let offset = add_pointer.offset as usize;
 
-        match add_pointer.size {
-            1 => add_value_at_offset(addend as u8, offset, pointer),
-            2 => add_value_at_offset(addend as u16, offset, pointer),
-            4 => add_value_at_offset(addend as u32, offset, pointer),
-            8 => add_value_at_offset(addend as u64, offset, pointer),
-            _ => (),
+        let size = match add_pointer.size {
+            s @ (1 | 2 | 4 | 8) => s as usize,
+            _ => return Err("Unsupported pointer size"),
+        };
+        let b = offset
+            .checked_add(size)
+            .and_then(|end| pointer.get_mut(offset..end))
+            .ok_or("Pointer offset out of bounds")?;
+
+        // The pointer field holds an offset into the pointee blob, to which we add its address
+        let mut val = [0u8; size_of::<u64>()];
+        val[..size].copy_from_slice(b);
+        let val = u64::from_le_bytes(val);
+        if val >= limit {
+            return Err("Pointer value out of range of pointee");
         }
+        let addr = addend + val;
+        if size < size_of::<u64>() && addr >> (8 * size) != 0 {
+            return Err("Pointer value does not fit");
+        }
+        b.copy_from_slice(&addr.to_le_bytes()[..size]);
         Ok(())
     }
 
     fn add_checksum(&mut self, add_checksum: &FwCfgLoaderAddChecksum) -> Result<(), &'static str> {
         let tables = &mut self.loaded_tables;
         let start = add_checksum.start as usize;
-        let end = start + add_checksum.size as usize;
+        let end = start
+            .checked_add(add_checksum.size as usize)
+            .ok_or("Checksum range out of bounds")?;
         let offset = add_checksum.result_offset as usize;
         let table = tables
             .get_mut(&add_checksum.filename)
             .ok_or("Unknown blob for checksum")?;
+        if end > table.len() {
+            return Err("Checksum range out of bounds");
+        }
+        if offset >= table.len() {
+            return Err("Checksum offset out of bounds");
+        }
         let mut checksum = 0u8;
         table[start..end]
             .iter()
@@ -507,93 +929,254 @@ impl<'a> FwCfgTableLoader<'a> {
         Ok(())
     }
 
+    fn write_pointer(
+        &mut self,
+        write_pointer: &FwCfgLoaderWritePointer,
+    ) -> Result<(), &'static str> {
+        let pointee = self
+            .loaded_tables
+            .get(&write_pointer.pointee)
+            .ok_or("Unknown pointee blob")?;
+        let pointee_offset = write_pointer.pointee_offset as usize;
+        if pointee_offset >= pointee.len() {
+            return Err("Pointee offset out of range");
+        }
+        let addr = pointee.as_ptr() as u64 + pointee_offset as u64;
+
+        let size = match write_pointer.size {
+            s @ (1 | 2 | 4 | 8) => s as usize,
+            _ => return Err("Unsupported pointer size"),
+        };
+        if size < size_of::<u64>() && addr >> (8 * size) != 0 {
+            return Err("Pointer value does not fit");
+        }
+
+        // The pointer lives in a writable fw_cfg file rather than in one of the
+        // loaded blobs, so pass the address back to the host via a DMA write.
+        let name = filename_str(&write_pointer.pointer).ok_or("Invalid pointer file name")?;
+        self.fwcfg.write_file(
+            name,
+            write_pointer.pointer_offset as usize,
+            &addr.to_le_bytes()[..size],
+        )
+    }
+
     pub fn load_firmware_tables(&mut self) -> Result<*const u8, &'static str> {
-        for entry in self.fwcfg.loader().ok_or("Failed to access table loader")? {
-            match entry._type {
-                FwCfgLoaderCmdType::FwCfgLoaderCmdAllocate => unsafe {
+        let loader = self.fwcfg.loader().ok_or("Failed to access table loader")?;
+
+        // Memory that the host refers to must remain reserved after boot, so find out
+        // which blobs are the target of a WRITE_POINTER command before allocating any.
+        for entry in loader.iter() {
+            if let Some(FwCfgLoaderCmdType::FwCfgLoaderCmdWritePointer) = entry.command() {
+                self.nvs_blobs
+                    .insert(unsafe { entry.u.write_pointer.pointee });
+            }
+        }
+
+        for entry in loader {
+            match entry.command() {
+                Some(FwCfgLoaderCmdType::FwCfgLoaderCmdAllocate) => unsafe {
                     self.allocate(&entry.u.allocate)
                 },
-                FwCfgLoaderCmdType::FwCfgLoaderCmdAddPointer => unsafe {
+                Some(FwCfgLoaderCmdType::FwCfgLoaderCmdAddPointer) => unsafe {
                     self.add_pointer(&entry.u.add_pointer)
                 },
-                FwCfgLoaderCmdType::FwCfgLoaderCmdAddChecksum => unsafe {
+                Some(FwCfgLoaderCmdType::FwCfgLoaderCmdAddChecksum) => unsafe {
                     self.add_checksum(&entry.u.add_checksum)
                 },
-                //FwCfgLoaderCmdType::FwCfgLoaderCmdWritePointer => unsafe {
-                //},
+                Some(FwCfgLoaderCmdType::FwCfgLoaderCmdWritePointer) => unsafe {
+                    self.write_pointer(&entry.u.write_pointer)
+                },
                 _ => Err("Unsupported table loader command"),
-            }?;
+            }
+            .inspect_err(|_| log::warn!("Table loader command failed: {:?}\n", entry))?;
         }
         let rsdp = self
             .loaded_tables
             .iter()
-            .find(|(&k, &ref _v)| {
-                from_utf8(&k).map_or(false, |s| s.trim_end_matches("\0") == "etc/acpi/rsdp")
-            })
+            .find(|(k, _)| filename_str(k) == Some("etc/acpi/rsdp"))
             .ok_or("Failed to locate RSDP table")?
             .1;
         Ok(rsdp.as_ptr())
     }
 }
 
-trait LeBytes<T, const N: usize> {
-    fn from_le_bytes(val: &[u8; N]) -> T;
-    fn to_le_bytes(val: T) -> [u8; N];
+// A fw_cfg item that is exposed as (part of) a file
+#[derive(Copy, Clone)]
+struct FwCfgFileChunk {
+    data_cfg: u16,
+    offset: usize, // of the chunk inside the file
+    size: usize,
 }
 
-macro_rules! le_bytes {
-    ($a: ty) => {
-        impl LeBytes<$a, { size_of::<$a>() }> for $a {
-            fn from_le_bytes(val: &[u8; { size_of::<$a>() }]) -> $a {
-                <$a>::from_le_bytes(*val)
-            }
-            fn to_le_bytes(val: $a) -> [u8; { size_of::<$a>() }] {
-                <$a>::to_le_bytes(val)
-            }
-        }
-    };
+// Parse a SHA-256 digest in the format produced by sha256sum, i.e., as 64 hex digits optionally
+// followed by whitespace and a file name
+fn parse_sha256(v: &[u8]) -> Option<[u8; SHA256_DIGEST_SIZE]> {
+    let s = from_utf8(v).ok()?.split_whitespace().next()?;
+    if s.len() != 2 * SHA256_DIGEST_SIZE || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut digest = [0u8; SHA256_DIGEST_SIZE];
+    for (i, b) in digest.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(digest)
 }
 
-le_bytes!(u8);
-le_bytes!(u16);
-le_bytes!(u32);
-le_bytes!(u64);
-
-fn add_value_at_offset<T: LeBytes<T, N> + core::ops::Add<Output = T>, const N: usize>(
-    addend: T,
-    offset: usize,
-    buf: &mut [u8],
-) {
-    let end = offset + N;
-    let val: T = T::from_le_bytes(buf[offset..end].try_into().unwrap()) + addend;
-    buf[offset..end].copy_from_slice(&T::to_le_bytes(val));
+struct FwCfgDigest {
+    // None if the host provided a digest that could not be parsed
+    expected: Option<[u8; SHA256_DIGEST_SIZE]>,
+    // the outcome of the comparison, once the file has been hashed
+    verified: Option<bool>,
 }
 
 pub struct FwCfgFileLoader<'a> {
     size: usize,
-    data_cfg: u16,
+    chunks: Vec<FwCfgFileChunk>,
     fwcfg: &'a FwCfg,
     preload: Box<[u8]>,
+    digest: RefCell<Option<FwCfgDigest>>,
 }
 
 impl<'a> FwCfgFileLoader<'a> {
-    fn new(size: usize, data_cfg: u16, fwcfg: &'a FwCfg, preload_size: usize) -> FwCfgFileLoader {
+    fn new(
+        size: usize,
+        data_cfg: u16,
+        fwcfg: &'a FwCfg,
+        preload_size: usize,
+    ) -> FwCfgFileLoader<'a> {
+        Self::concat(&[(data_cfg, size)], 1, fwcfg, preload_size)
+    }
+
+    /// Create a loader for the named fw_cfg file, caching the first preload_size bytes
+    pub fn from_file(
+        name: &str,
+        fwcfg: &'a FwCfg,
+        preload_size: usize,
+    ) -> Option<FwCfgFileLoader<'a>> {
+        let f = fwcfg.find_file(name)?;
+        if f.size() == 0 {
+            return None;
+        }
+        Some(Self::new(f.size(), f.select(), fwcfg, preload_size))
+    }
+
+    // Expose the concatenation of a set of fw_cfg items as a single file, with each item
+    // starting at an offset that is a multiple of align
+    fn concat(
+        items: &[(u16, usize)],
+        align: usize,
+        fwcfg: &'a FwCfg,
+        preload_size: usize,
+    ) -> FwCfgFileLoader<'a> {
+        let mut size: usize = 0;
+        let chunks = items
+            .iter()
+            .map(|&(data_cfg, item_size)| {
+                let offset = size.next_multiple_of(align);
+                size = offset + item_size;
+                FwCfgFileChunk {
+                    data_cfg,
+                    offset,
+                    size: item_size,
+                }
+            })
+            .collect();
+
+        let mut loader = FwCfgFileLoader {
+            size,
+            chunks,
+            fwcfg,
+            preload: Box::new([]),
+            digest: RefCell::new(None),
+        };
+
         let preload_size = size.min(preload_size);
-        let mut preload = Vec::<u8>::new();
         if preload_size > 0 {
-            let mut buf = Vec::<MaybeUninit<u8>>::new();
-            buf.resize(preload_size, MaybeUninit::uninit());
+            let mut buf = Vec::<u8>::with_capacity(preload_size);
+            if loader.read(buf.spare_capacity_mut(), 0).is_ok() {
+                unsafe {
+                    buf.set_len(preload_size);
+                }
+                loader.preload = buf.into_boxed_slice();
+            }
+        }
+        loader
+    }
 
-            if let Ok(ret) = fwcfg.load_file(buf.as_mut_slice(), 0, preload_size, data_cfg) {
-                preload = ret.to_vec();
+    // Populate buf with the file contents starting at offset. Any parts of buf that are not
+    // covered by a chunk (i.e., padding, or data beyond the end of the file) are zeroed.
+    fn read(&self, buf: &mut [MaybeUninit<u8>], offset: usize) -> Result<(), ()> {
+        let end = offset.saturating_add(buf.len());
+        let mut pos = offset;
+        for c in self.chunks.iter() {
+            if c.offset + c.size <= pos || c.size == 0 {
+                continue;
+            }
+            if c.offset >= end {
+                break;
             }
+            if c.offset > pos {
+                buf[pos - offset..c.offset - offset].fill(MaybeUninit::zeroed());
+                pos = c.offset;
+            }
+            let n = end.min(c.offset + c.size) - pos;
+            self.fwcfg
+                .dma_read(&mut buf[pos - offset..], pos - c.offset, n, c.data_cfg)?;
+            pos += n;
         }
+        buf[pos - offset..].fill(MaybeUninit::zeroed());
+        Ok(())
+    }
 
-        FwCfgFileLoader {
-            size: size,
-            data_cfg: data_cfg,
-            fwcfg: fwcfg,
-            preload: preload.into_boxed_slice(),
+    // Verify the file contents against the SHA-256 digest in the named fw_cfg file, if it exists
+    fn with_digest(self, name: &str) -> Self {
+        let Some(v) = self.fwcfg.read_file(name) else {
+            return self;
+        };
+        let expected = parse_sha256(&v);
+        if expected.is_none() {
+            log::error!("Failed to parse SHA-256 digest in {}\n", name);
+        }
+        self.digest.replace(Some(FwCfgDigest {
+            expected,
+            verified: None,
+        }));
+        self
+    }
+
+    /// Read the entire file and check its contents against the digest provided by the host.
+    /// Succeeds trivially if the host did not provide a digest. Note that this is a full read of
+    /// the file from fw_cfg, on top of the one needed to load it.
+    pub fn verify(&self) -> Result<(), &'static str> {
+        let mut digest = self.digest.borrow_mut();
+        let Some(d) = digest.as_mut() else {
+            return Ok(());
+        };
+        if d.verified.is_none() {
+            let mut hasher = Sha256::new();
+            let mut buf = Vec::<u8>::with_capacity(CFG_DIGEST_BOUNCE_SIZE);
+            let mut pos = 0;
+            while pos < self.size {
+                let n = (self.size - pos).min(CFG_DIGEST_BOUNCE_SIZE);
+                buf.clear();
+                self.read(&mut buf.spare_capacity_mut()[..n], pos)
+                    .or(Err("Failed to read file from fwcfg"))?;
+                unsafe {
+                    buf.set_len(n);
+                }
+                hasher.update(&buf);
+                pos += n;
+            }
+            let actual = hasher.finalize();
+            if d.expected != Some(actual) {
+                log::error!("SHA-256 digest mismatch: {:02x?}\n", actual);
+            }
+            d.verified = Some(d.expected == Some(actual));
+        }
+        match d.verified {
+            Some(true) => Ok(()),
+            _ => Err("SHA-256 digest mismatch"),
         }
     }
 }
@@ -604,25 +1187,28 @@ impl efiloader::FileLoader for FwCfgFileLoader<'_> {
     }
 
     fn load_file<'a>(&self, loadbuffer: &'a mut [MaybeUninit<u8>]) -> Result<&'a [u8], &str> {
-        self.fwcfg
-            .load_file(loadbuffer, 0, self.size, self.data_cfg)
-            .or(Err("Failed to load file from fwcfg"))
+        self.read(loadbuffer, 0)
+            .or(Err("Failed to load file from fwcfg"))?;
+        unsafe {
+            Ok(slice::from_raw_parts(
+                loadbuffer.as_ptr() as *const _,
+                loadbuffer.len(),
+            ))
+        }
     }
 
     unsafe fn load_range<'a>(&self, ptr: *mut (), offset: usize, size: usize) -> Result<(), &str> {
         if offset > self.size {
             return Err("Offset out of range");
         }
-        if offset + size <= self.preload.len() {
+        if offset.saturating_add(size) <= self.preload.len() {
             log::trace!("Reading from preload vector {:?}\n", offset..offset + size);
             let p = self.preload.as_ptr();
             core::ptr::copy(p.offset(offset as isize), ptr as *mut u8, size);
             return Ok(());
         }
         let loadbuffer = slice::from_raw_parts_mut(ptr as *mut MaybeUninit<u8>, size);
-        self.fwcfg
-            .load_file(loadbuffer, offset, size, self.data_cfg)
+        self.read(loadbuffer, offset)
             .or(Err("Failed to load range from fwcfg"))
-            .and(Ok(()))
     }
 }
@@ -0,0 +1,477 @@
+// SPDX-License-Identifier: GPL-2.0
+// Copyright 2026 Google LLC
+
+//! A read-only EFI Simple File System volume that exposes the fw_cfg file directory, so that
+//! loaded images can access files passed via QEMU's -fw_cfg option. Path separators in fw_cfg
+//! file names are mapped onto directories, e.g., opt/foo/bar becomes \opt\foo\bar.
+
+use crate::fwcfg::{FwCfg, FwCfgFileLoader};
+
+use alloc::{boxed::Box, collections::BTreeMap, string::*, vec::Vec};
+use core::{mem::size_of, ptr, slice};
+use efiloader::status::Status;
+use efiloader::status::Status::*;
+use efiloader::*;
+
+const EFI_SIMPLE_FILE_SYSTEM_PROTOCOL_GUID: Guid = guid!(
+    0x964e5b22,
+    0x6459,
+    0x11d2,
+    [0x8e, 0x39, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b]
+);
+
+const EFI_DEVICE_PATH_PROTOCOL_GUID: Guid = guid!(
+    0x09576e91,
+    0x6d3f,
+    0x11d2,
+    [0x8e, 0x39, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b]
+);
+
+const EFI_FILE_INFO_ID: Guid = guid!(
+    0x09576e92,
+    0x6d3f,
+    0x11d2,
+    [0x8e, 0x39, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b]
+);
+
+const EFI_FILE_SYSTEM_INFO_ID: Guid = guid!(
+    0x09576e93,
+    0x6d3f,
+    0x11d2,
+    [0x8e, 0x39, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b]
+);
+
+// Vendor GUID used in the device path of the fw_cfg volume
+const FWCFGFS_VENDOR_GUID: Guid = guid!(
+    0x6b5a8e2c,
+    0x3f4d,
+    0x4f1b,
+    [0x9a, 0x7e, 0x0c, 0x1d, 0x2e, 0x3f, 0x4a, 0x5b]
+);
+
+const EFI_SIMPLE_FILE_SYSTEM_PROTOCOL_REVISION: u64 = 0x00010000;
+const EFI_FILE_PROTOCOL_REVISION: u64 = 0x00010000;
+
+const EFI_FILE_MODE_READ: u64 = 0x1;
+
+const EFI_FILE_READ_ONLY: u64 = 0x1;
+const EFI_FILE_DIRECTORY: u64 = 0x10;
+
+// Size of the fixed parts of EFI_FILE_INFO and EFI_FILE_SYSTEM_INFO
+const SIZE_OF_EFI_FILE_INFO: usize = 80;
+const SIZE_OF_EFI_FILE_SYSTEM_INFO: usize = 36;
+
+const VOLUME_LABEL: &str = "fwcfg";
+
+#[repr(C)]
+struct SimpleFileSystemProtocol {
+    revision: u64,
+    open_volume:
+        extern "efiapi" fn(*mut SimpleFileSystemProtocol, *mut *mut FileProtocol) -> Status,
+}
+
+#[repr(C)]
+struct FileProtocol {
+    revision: u64,
+    open: extern "efiapi" fn(
+        *mut FileProtocol,
+        *mut *mut FileProtocol,
+        *const u16,
+        u64,
+        u64,
+    ) -> Status,
+    close: extern "efiapi" fn(*mut FileProtocol) -> Status,
+    delete: extern "efiapi" fn(*mut FileProtocol) -> Status,
+    read: extern "efiapi" fn(*mut FileProtocol, *mut usize, *mut ()) -> Status,
+    write: extern "efiapi" fn(*mut FileProtocol, *mut usize, *const ()) -> Status,
+    get_position: extern "efiapi" fn(*mut FileProtocol, *mut u64) -> Status,
+    set_position: extern "efiapi" fn(*mut FileProtocol, u64) -> Status,
+    get_info: extern "efiapi" fn(*mut FileProtocol, *const Guid, *mut usize, *mut ()) -> Status,
+    set_info: extern "efiapi" fn(*mut FileProtocol, *const Guid, usize, *const ()) -> Status,
+    flush: extern "efiapi" fn(*mut FileProtocol) -> Status,
+}
+
+#[repr(C)]
+struct VendorDevicePath {
+    _type: u8,
+    subtype: u8,
+    length: [u8; 2],
+    guid: Guid,
+    end: [u8; 4],
+}
+
+impl EfiProtocol for VendorDevicePath {
+    fn guid(&self) -> &'static Guid {
+        &EFI_DEVICE_PATH_PROTOCOL_GUID
+    }
+}
+
+enum Node {
+    Dir(BTreeMap<String, Node>),
+    File(String, usize), // fw_cfg file name and size
+}
+
+impl Node {
+    fn lookup(&self, path: &[String]) -> Option<&Node> {
+        let Some((first, rest)) = path.split_first() else {
+            return Some(self);
+        };
+        let Node::Dir(d) = self else {
+            return None;
+        };
+        // Paths on EFI file systems are usually case insensitive
+        d.get(first)
+            .or_else(|| {
+                d.iter()
+                    .find(|(k, _)| k.eq_ignore_ascii_case(first))
+                    .map(|(_, v)| v)
+            })?
+            .lookup(rest)
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Node::Dir(d) => d.values().map(|n| n.size()).sum(),
+            Node::File(_, size) => *size,
+        }
+    }
+
+    fn info(&self, name: &str) -> Vec<u8> {
+        let (file_size, attr) = match self {
+            Node::Dir(_) => (0, EFI_FILE_READ_ONLY | EFI_FILE_DIRECTORY),
+            Node::File(_, size) => (*size as u64, EFI_FILE_READ_ONLY),
+        };
+        let name: Vec<u16> = name.encode_utf16().chain([0]).collect();
+        let size = SIZE_OF_EFI_FILE_INFO + name.len() * size_of::<u16>();
+
+        let mut v = Vec::with_capacity(size);
+        v.extend_from_slice(&(size as u64).to_le_bytes());
+        v.extend_from_slice(&file_size.to_le_bytes()); // FileSize
+        v.extend_from_slice(&file_size.to_le_bytes()); // PhysicalSize
+        v.resize(v.len() + 3 * 16, 0); // Create/LastAccess/ModificationTime
+        v.extend_from_slice(&attr.to_le_bytes());
+        name.iter()
+            .for_each(|c| v.extend_from_slice(&c.to_le_bytes()));
+        v
+    }
+}
+
+#[repr(C)]
+struct FwCfgFs {
+    proto: SimpleFileSystemProtocol,
+    fwcfg: &'static FwCfg,
+    root: Node,
+}
+
+impl EfiProtocol for FwCfgFs {
+    fn guid(&self) -> &'static Guid {
+        &EFI_SIMPLE_FILE_SYSTEM_PROTOCOL_GUID
+    }
+}
+
+#[repr(C)]
+struct FileHandle {
+    proto: FileProtocol,
+    fs: &'static FwCfgFs,
+    path: Vec<String>,
+    node: &'static Node,
+    loader: Option<FwCfgFileLoader<'static>>,
+    // byte offset for files, index of the next entry for directories
+    position: u64,
+}
+
+impl FileHandle {
+    fn open(fs: &'static FwCfgFs, path: Vec<String>) -> Option<*mut FileProtocol> {
+        let node = fs.root.lookup(&path)?;
+        let loader = match node {
+            Node::File(name, size) if *size > 0 => {
+                Some(FwCfgFileLoader::from_file(name, fs.fwcfg, 0)?)
+            }
+            _ => None,
+        };
+        let h = Box::new(FileHandle {
+            proto: FileProtocol {
+                revision: EFI_FILE_PROTOCOL_REVISION,
+                open: file_open,
+                close: file_close,
+                delete: file_delete,
+                read: file_read,
+                write: file_write,
+                get_position: file_get_position,
+                set_position: file_set_position,
+                get_info: file_get_info,
+                set_info: file_set_info,
+                flush: file_flush,
+            },
+            fs,
+            path,
+            node,
+            loader,
+            position: 0,
+        });
+        Some(Box::into_raw(h) as *mut FileProtocol)
+    }
+
+    fn name(&self) -> &str {
+        self.path.last().map_or("", |s| s.as_str())
+    }
+}
+
+// SAFETY: the caller must pass a FileProtocol pointer produced by FileHandle::open()
+unsafe fn handle<'a>(this: *mut FileProtocol) -> &'a mut FileHandle {
+    &mut *(this as *mut FileHandle)
+}
+
+// Copy a variable sized info structure into a caller provided buffer
+fn copy_info(info: &[u8], size: *mut usize, buf: *mut ()) -> Status {
+    unsafe {
+        if *size < info.len() {
+            *size = info.len();
+            return EFI_BUFFER_TOO_SMALL;
+        }
+        if buf.is_null() {
+            return EFI_INVALID_PARAMETER;
+        }
+        ptr::copy_nonoverlapping(info.as_ptr(), buf as *mut u8, info.len());
+        *size = info.len();
+    }
+    EFI_SUCCESS
+}
+
+extern "efiapi" fn open_volume(
+    this: *mut SimpleFileSystemProtocol,
+    root: *mut *mut FileProtocol,
+) -> Status {
+    if this.is_null() || root.is_null() {
+        return EFI_INVALID_PARAMETER;
+    }
+    let fs = unsafe { &*(this as *const FwCfgFs) };
+    match FileHandle::open(fs, Vec::new()) {
+        Some(h) => {
+            unsafe { *root = h };
+            EFI_SUCCESS
+        }
+        None => EFI_DEVICE_ERROR,
+    }
+}
+
+extern "efiapi" fn file_open(
+    this: *mut FileProtocol,
+    new: *mut *mut FileProtocol,
+    name: *const u16,
+    mode: u64,
+    _attributes: u64,
+) -> Status {
+    if this.is_null() || new.is_null() || name.is_null() {
+        return EFI_INVALID_PARAMETER;
+    }
+    if mode != EFI_FILE_MODE_READ {
+        return EFI_WRITE_PROTECTED;
+    }
+    let h = unsafe { handle(this) };
+    let name = unsafe {
+        let len = (0..).take_while(|&i| *name.add(i) != 0).count();
+        match String::from_utf16(slice::from_raw_parts(name, len)) {
+            Ok(s) => s,
+            Err(_) => return EFI_NOT_FOUND,
+        }
+    };
+
+    let mut path = if name.starts_with('\\') {
+        Vec::new()
+    } else {
+        h.path.clone()
+    };
+    for c in name.split('\\') {
+        match c {
+            "" | "." => (),
+            ".." => {
+                path.pop();
+            }
+            c => path.push(c.to_string()),
+        }
+    }
+
+    match FileHandle::open(h.fs, path) {
+        Some(f) => {
+            unsafe { *new = f };
+            EFI_SUCCESS
+        }
+        None => EFI_NOT_FOUND,
+    }
+}
+
+extern "efiapi" fn file_close(this: *mut FileProtocol) -> Status {
+    if this.is_null() {
+        return EFI_INVALID_PARAMETER;
+    }
+    drop(unsafe { Box::from_raw(this as *mut FileHandle) });
+    EFI_SUCCESS
+}
+
+extern "efiapi" fn file_delete(this: *mut FileProtocol) -> Status {
+    // The handle must be closed even though the file cannot be deleted. This should return
+    // EFI_WARN_DELETE_FAILURE, but efiloader's Status type cannot express warnings, so report
+    // that the volume is read-only instead.
+    file_close(this);
+    EFI_WRITE_PROTECTED
+}
+
+extern "efiapi" fn file_read(this: *mut FileProtocol, size: *mut usize, buf: *mut ()) -> Status {
+    if this.is_null() || size.is_null() {
+        return EFI_INVALID_PARAMETER;
+    }
+    let h = unsafe { handle(this) };
+    match h.node {
+        Node::Dir(d) => {
+            let Some((name, node)) = d.iter().nth(h.position as usize) else {
+                // no more entries
+                unsafe { *size = 0 };
+                return EFI_SUCCESS;
+            };
+            let ret = copy_info(&node.info(name), size, buf);
+            if let EFI_SUCCESS = ret {
+                h.position += 1;
+            }
+            ret
+        }
+        Node::File(_, file_size) => {
+            let pos = h.position as usize;
+            if pos > *file_size {
+                return EFI_DEVICE_ERROR;
+            }
+            let n = unsafe { *size }.min(file_size - pos);
+            if n > 0 {
+                let loader = h.loader.as_ref().unwrap();
+                if unsafe { loader.load_range(buf, pos, n) }.is_err() {
+                    return EFI_DEVICE_ERROR;
+                }
+            }
+            h.position += n as u64;
+            unsafe { *size = n };
+            EFI_SUCCESS
+        }
+    }
+}
+
+extern "efiapi" fn file_write(_this: *mut FileProtocol, _: *mut usize, _: *const ()) -> Status {
+    EFI_WRITE_PROTECTED
+}
+
+extern "efiapi" fn file_get_position(this: *mut FileProtocol, pos: *mut u64) -> Status {
+    if this.is_null() || pos.is_null() {
+        return EFI_INVALID_PARAMETER;
+    }
+    let h = unsafe { handle(this) };
+    if let Node::Dir(_) = h.node {
+        return EFI_UNSUPPORTED;
+    }
+    unsafe { *pos = h.position };
+    EFI_SUCCESS
+}
+
+extern "efiapi" fn file_set_position(this: *mut FileProtocol, pos: u64) -> Status {
+    if this.is_null() {
+        return EFI_INVALID_PARAMETER;
+    }
+    let h = unsafe { handle(this) };
+    match h.node {
+        // Only rewinding is supported for directories
+        Node::Dir(_) if pos != 0 => return EFI_UNSUPPORTED,
+        Node::File(_, size) if pos == u64::MAX => h.position = *size as u64,
+        _ => h.position = pos,
+    }
+    EFI_SUCCESS
+}
+
+extern "efiapi" fn file_get_info(
+    this: *mut FileProtocol,
+    info_type: *const Guid,
+    size: *mut usize,
+    buf: *mut (),
+) -> Status {
+    if this.is_null() || info_type.is_null() || size.is_null() {
+        return EFI_INVALID_PARAMETER;
+    }
+    let h = unsafe { handle(this) };
+    let info_type = unsafe { &*info_type };
+
+    if *info_type == EFI_FILE_INFO_ID {
+        copy_info(&h.node.info(h.name()), size, buf)
+    } else if *info_type == EFI_FILE_SYSTEM_INFO_ID {
+        let label: Vec<u16> = VOLUME_LABEL.encode_utf16().chain([0]).collect();
+        let len = SIZE_OF_EFI_FILE_SYSTEM_INFO + label.len() * size_of::<u16>();
+
+        let mut v = Vec::with_capacity(len);
+        v.extend_from_slice(&(len as u64).to_le_bytes());
+        v.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0]); // ReadOnly
+        v.extend_from_slice(&(h.fs.root.size() as u64).to_le_bytes()); // VolumeSize
+        v.extend_from_slice(&0u64.to_le_bytes()); // FreeSpace
+        v.extend_from_slice(&512u32.to_le_bytes()); // BlockSize
+        label
+            .iter()
+            .for_each(|c| v.extend_from_slice(&c.to_le_bytes()));
+        copy_info(&v, size, buf)
+    } else {
+        EFI_UNSUPPORTED
+    }
+}
+
+extern "efiapi" fn file_set_info(
+    _this: *mut FileProtocol,
+    _info_type: *const Guid,
+    _size: usize,
+    _buf: *const (),
+) -> Status {
+    EFI_WRITE_PROTECTED
+}
+
+extern "efiapi" fn file_flush(_this: *mut FileProtocol) -> Status {
+    EFI_SUCCESS
+}
+
+/// Publish the fw_cfg file directory as a Simple File System protocol instance on a new handle
+pub fn install(fwcfg: &'static FwCfg, efi: &EfiContext) {
+    let mut root = Node::Dir(BTreeMap::new());
+    for (name, f) in fwcfg.files() {
+        let mut dir = &mut root;
+        let mut components = name.split('/').filter(|c| !c.is_empty()).peekable();
+        while let Some(c) = components.next() {
+            let Node::Dir(d) = dir else {
+                log::warn!(
+                    "Ignoring fwcfg file {} that conflicts with another file\n",
+                    name
+                );
+                break;
+            };
+            dir = d
+                .entry(c.to_string())
+                .or_insert_with(|| match components.peek() {
+                    Some(_) => Node::Dir(BTreeMap::new()),
+                    None => Node::File(name.clone(), f.size()),
+                });
+        }
+    }
+
+    let fs = FwCfgFs {
+        proto: SimpleFileSystemProtocol {
+            revision: EFI_SIMPLE_FILE_SYSTEM_PROTOCOL_REVISION,
+            open_volume,
+        },
+        fwcfg,
+        root,
+    };
+
+    let dp = VendorDevicePath {
+        _type: 1,   // hardware device path
+        subtype: 4, // vendor
+        length: (size_of::<VendorDevicePath>() as u16 - 4).to_le_bytes(),
+        guid: FWCFGFS_VENDOR_GUID,
+        end: [0x7f, 0xff, 4, 0],
+    };
+
+    // The protocol database keeps both structs pinned for as long as the protocols remain
+    // installed, which is never undone, so open file handles may refer to the volume freely
+    let h = efi.install_protocol(None, dp);
+    efi.install_protocol(Some(h), fs);
+}
@@ -0,0 +1,211 @@
+// SPDX-License-Identifier: GPL-2.0
+// Copyright 2026 Google LLC
+
+//! Firmware log ring buffer
+//!
+//! All log records are kept in a ring buffer in memory, regardless of whether a console is
+//! available, so that the OS can retrieve them after boot. The buffer is published as an EFI
+//! configuration table, and resides in EfiRuntimeServicesData memory. It consists of the
+//! following header, followed by `size` bytes of text:
+//!
+//!   offset  size  field
+//!        0     8  signature, "EFILTLOG"
+//!        8     4  version, currently 1
+//!       12     4  header size in bytes, i.e., the offset of the text
+//!       16     8  size of the text area in bytes
+//!       24     8  total number of bytes written to the text area so far
+//!
+//! All fields are little endian. If fewer than `size` bytes have been written, the log consists of
+//! the text written so far, starting at the beginning of the text area. Otherwise, the buffer has
+//! wrapped around, and the oldest byte is at offset `written % size` into the text area.
+
+use crate::console::SerialConsole;
+use crate::logfilter::LogFilter;
+
+use core::cell::{Cell, RefCell};
+use core::fmt::Write;
+use core::mem::size_of;
+use core::ptr::addr_of_mut;
+use efiloader::memmap::{self, Placement};
+use efiloader::memorytype::EfiMemoryType;
+use efiloader::EfiContext;
+use log::{Log, Metadata, Record};
+use once_cell::unsync::OnceCell;
+
+const LOG_SIGNATURE: [u8; 8] = *b"EFILTLOG";
+const LOG_VERSION: u32 = 1;
+
+// Size of the statically allocated buffer used until the EFI memory allocator is up
+const EARLY_LOG_SIZE: usize = 16 * 1024;
+
+// Size of the buffer that is published to the OS
+const LOG_SIZE: usize = 64 * 1024;
+
+#[repr(C)]
+struct LogBufferHeader {
+    signature: [u8; 8],
+    version: u32,
+    header_size: u32,
+    size: u64,
+    written: u64,
+}
+
+struct LogBuffer {
+    hdr: *mut LogBufferHeader,
+    text: *mut u8,
+    size: usize,
+}
+
+// SAFETY: a LogBuffer is the sole user of the memory it points to, which is either the statically
+// allocated early buffer that is only ever wrapped by the one Logger instance, or the buffer that
+// Logger::publish() allocated for it.
+unsafe impl Send for LogBuffer {}
+
+impl LogBuffer {
+    // SAFETY: mem must point to a buffer of at least size + size_of::<LogBufferHeader>() bytes
+    unsafe fn new(mem: *mut u8, size: usize) -> Self {
+        let hdr = mem as *mut LogBufferHeader;
+        hdr.write(LogBufferHeader {
+            signature: LOG_SIGNATURE,
+            version: LOG_VERSION,
+            header_size: size_of::<LogBufferHeader>() as u32,
+            size: size as u64,
+            written: 0,
+        });
+        LogBuffer {
+            hdr,
+            text: mem.add(size_of::<LogBufferHeader>()),
+            size,
+        }
+    }
+
+    fn written(&self) -> u64 {
+        // SAFETY: hdr points to a valid header
+        unsafe { (*self.hdr).written }
+    }
+
+    // Copy the contents of another log buffer, oldest data first
+    fn copy_from(&mut self, other: &LogBuffer) {
+        let written = other.written();
+        let (start, len) = if written < other.size as u64 {
+            (0, written as usize)
+        } else {
+            ((written % other.size as u64) as usize, other.size)
+        };
+        for i in 0..len {
+            // SAFETY: the index is taken modulo the size of the text area
+            let b = unsafe { *other.text.add((start + i) % other.size) };
+            self.push(b);
+        }
+    }
+
+    fn push(&mut self, b: u8) {
+        // SAFETY: hdr points to a valid header, and the index into text[] is taken modulo
+        // its size
+        unsafe {
+            let written = (*self.hdr).written;
+            *self.text.add((written % self.size as u64) as usize) = b;
+            (*self.hdr).written = written + 1;
+        }
+    }
+}
+
+impl Write for LogBuffer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        s.bytes().for_each(|b| self.push(b));
+        Ok(())
+    }
+}
+
+pub struct Logger {
+    buf: RefCell<LogBuffer>,
+    console: Cell<Option<&'static SerialConsole>>,
+    filter: RefCell<LogFilter>,
+}
+
+// SAFETY: the only Logger instance is handed out by logger() as a shared reference, and all of its
+// mutable state lives in RefCells (the log buffer and the filter) or a Cell (the console, which is
+// only ever replaced as a whole). EFI boot services are single threaded, and the only way we might
+// enter recursively is when a panic is triggered while a record is being written, which is why the
+// RefCells are only accessed via try_borrow() and try_borrow_mut().
+unsafe impl Sync for Logger {}
+
+/// Return the logger, which records everything in the log buffer and forwards it to the console
+pub fn logger() -> &'static Logger {
+    // Statically allocated so we can log before the heap is up
+    static mut EARLY_LOG: [u64; (EARLY_LOG_SIZE + size_of::<LogBufferHeader>()) / 8] =
+        [0; (EARLY_LOG_SIZE + size_of::<LogBufferHeader>()) / 8];
+    static mut LOGGER: OnceCell<Logger> = OnceCell::new();
+
+    // SAFETY: the code is single threaded and does not recurse, so the first invocation will
+    // run to completion before this code is ever executed again.
+    unsafe {
+        (*addr_of_mut!(LOGGER)).get_or_init(|| Logger {
+            buf: RefCell::new(LogBuffer::new(
+                addr_of_mut!(EARLY_LOG) as *mut u8,
+                EARLY_LOG_SIZE,
+            )),
+            console: Cell::new(None),
+            filter: RefCell::new(LogFilter::default()),
+        })
+    }
+}
+
+impl Logger {
+    pub fn set_console(&self, con: &'static SerialConsole) {
+        self.console.set(Some(con));
+    }
+
+    /// Replace the log filter, and raise or lower the global maximum level accordingly
+    pub fn set_filter(&self, filter: LogFilter) {
+        log::set_max_level(filter.max_level());
+        if let Ok(mut f) = self.filter.try_borrow_mut() {
+            *f = filter;
+        }
+    }
+
+    /// Move the log into memory that is retained after boot, and return its address so it can
+    /// be published as a configuration table
+    pub fn publish(&self, efi: &EfiContext) -> Option<*const u8> {
+        let size = size_of::<LogBufferHeader>() + LOG_SIZE;
+        let mem = efi.allocate_pages(
+            memmap::size_to_pages(size),
+            EfiMemoryType::EfiRuntimeServicesData,
+            Placement::Anywhere,
+        )?;
+
+        // SAFETY: mem[] is large enough to hold the header and LOG_SIZE bytes of text
+        let mut buf = unsafe { LogBuffer::new(mem.as_mut_ptr() as *mut u8, LOG_SIZE) };
+        let mut cur = self.buf.try_borrow_mut().ok()?;
+        buf.copy_from(&cur);
+        *cur = buf;
+        Some(cur.hdr as *const u8)
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+            && self
+                .filter
+                .try_borrow()
+                .map_or(true, |f| metadata.level() <= f.level(metadata.target()))
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            if let Ok(mut buf) = self.buf.try_borrow_mut() {
+                write!(buf, "efilite {} - {}", record.level(), record.args()).ok();
+            }
+            if let Some(con) = self.console.get() {
+                con.log(record);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(con) = self.console.get() {
+            con.flush();
+        }
+    }
+}
@@ -0,0 +1,67 @@
+// SPDX-License-Identifier: GPL-2.0
+// Copyright 2026 Google LLC
+
+//! Log level filtering, configurable at boot using a specification such as
+//! "info,fwcfg=trace,mapper=warn", i.e., a comma separated list of entries that are either a
+//! level, which sets the default, or a module=level pair, which overrides it for that module.
+//! Modules are given by their path inside efilite (e.g., "fwcfg") or by the name of another
+//! crate (e.g., "efiloader").
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::str::FromStr;
+use log::LevelFilter;
+
+#[cfg(debug_assertions)]
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::Debug;
+
+#[cfg(not(debug_assertions))]
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::Warn;
+
+#[derive(Default)]
+pub struct LogFilter {
+    default: Option<LevelFilter>,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl LogFilter {
+    /// Parse a filter specification, returning None if any of its entries is invalid
+    pub fn parse(spec: &str) -> Option<LogFilter> {
+        let mut filter = LogFilter::default();
+        for entry in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            match entry.split_once('=') {
+                Some((module, level)) => {
+                    let module = module.trim().trim_start_matches("efilite::");
+                    let level = LevelFilter::from_str(level.trim()).ok()?;
+                    filter.modules.push((module.to_string(), level));
+                }
+                None => filter.default = Some(LevelFilter::from_str(entry).ok()?),
+            }
+        }
+        Some(filter)
+    }
+
+    /// Return the level that applies to log records with the given target, which is the module
+    /// path of the code that emitted it
+    pub fn level(&self, target: &str) -> LevelFilter {
+        let path = target.strip_prefix("efilite::").unwrap_or(target);
+
+        // The most specific matching module takes precedence
+        self.modules
+            .iter()
+            .filter(|(m, _)| {
+                path.strip_prefix(m.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(m, _)| m.len())
+            .map_or(self.default.unwrap_or(DEFAULT_LEVEL), |(_, l)| *l)
+    }
+
+    /// Return the most verbose level used by any part of this filter
+    pub fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, l)| *l)
+            .fold(self.default.unwrap_or(DEFAULT_LEVEL), Ord::max)
+    }
+}
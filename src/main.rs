@@ -17,20 +17,32 @@ macro_rules! ldrange {
     };
 }
 
+mod boot;
+mod config;
 mod console;
 mod fwcfg;
+mod fwcfgfs;
+mod logbuf;
+mod logfilter;
 mod mapper;
 mod pl031;
 mod psci;
 mod rng;
+mod sha256;
+mod smbios;
+mod timer;
+mod version;
+mod virtio;
 
 use core::mem::MaybeUninit;
 use core::{arch::global_asm, panic::PanicInfo};
 use core::ptr::addr_of_mut;
+use core::slice;
 use linked_list_allocator::LockedHeap;
 use log::{debug, error, info};
 
 extern crate alloc;
+use alloc::string::String;
 use alloc::vec::Vec;
 
 #[cfg(feature = "use_optimized_intrinsics")]
@@ -57,6 +69,20 @@ const RSDP_GUID: Guid = guid!(
     [0xbc, 0x22, 0x00, 0x80, 0xc7, 0x3c, 0x88, 0x81]
 );
 
+const EFILITE_LOG_GUID: Guid = guid!(
+    0x4e7a1c35,
+    0x9b1f,
+    0x4c8e,
+    [0xa6, 0x5d, 0x21, 0x70, 0x3b, 0x9e, 0x48, 0xf2]
+);
+
+const SMBIOS_GUID: Guid = guid!(
+    0xeb9d2d31,
+    0x2d88,
+    0x11d3,
+    [0x9a, 0x16, 0x00, 0x90, 0x27, 0x3f, 0xc1, 0x4d]
+);
+
 const SMBIOS3_GUID: Guid = guid!(
     0xf2fd1544,
     0x9794,
@@ -64,6 +90,12 @@ const SMBIOS3_GUID: Guid = guid!(
     [0x99, 0x2e, 0xe5, 0xbb, 0xcf, 0x20, 0xe3, 0x94]
 );
 
+// The command line argument that sets the log filter
+const LOGLEVEL_ARG: &str = "efilite.loglevel=";
+
+// The fwcfg sources of the EFI payload, in order of preference
+const DEFAULT_BOOT_SOURCES: &[&str] = &[fwcfg::CFG_BOOT_SOURCE_KERNEL, "opt/org.efilite/boot.efi"];
+
 #[global_allocator]
 pub static ALLOCATOR: LockedHeap = LockedHeap::empty();
 
@@ -79,40 +111,57 @@ extern "C" fn efilite_main(base: *mut u8, used: isize, avail: usize) {
         Ok(f) => f,
     };
 
-    #[cfg(debug_assertions)]
-    log::set_max_level(log::LevelFilter::Debug);
+    log::set_max_level(logfilter::DEFAULT_LEVEL);
 
-    #[cfg(not(debug_assertions))]
-    log::set_max_level(log::LevelFilter::Warn);
+    // Keep a record of all log output in memory, regardless of whether we have a console
+    let logger = logbuf::logger();
+    log::set_logger(logger).ok();
 
-    // Use the stdout-path as the console - assume it refers to a UART
-    // whose first 'reg' property describes a MMIO register that is
-    // compatible with our SimpleConsole implementation.
+    // Use the stdout-path as the console - assume it refers to a PL011 or 16550
+    // compatible UART whose first 'reg' property describes its MMIO registers.
+    // Fall back to a virtio console if there is no stdout-path.
     let con = fdt
         .chosen()
         .stdout()
+        .or_else(|| virtio::find_console(&fdt))
         .map(|n| {
-            let c = console::init_from_fdt_node(n)?;
-            log::set_logger(c).ok()?;
+            let c = console::init_from_fdt_node(&fdt, n)?;
+            logger.set_console(c);
             info!("Using {} for console output\n", n.name);
             Some(c)
         })
         .flatten();
 
+    info!(
+        "{} {} (build {})\n",
+        version::VENDOR,
+        version::VERSION,
+        version::BUILD_ID
+    );
+
     // Give the mapped but unused memory to the heap allocator
     unsafe {
         ALLOCATOR.lock().init(base.offset(used), avail);
     }
     info!("Heap allocator with {} KB of memory\n", avail / 1024);
 
-    // Grab the command line from DT and convert it to UTF-16
-    let cmdline = {
-        let mut v = Vec::new();
-        fdt.chosen().bootargs().map(|args| {
-            info!("Using command line from /chosen/bootargs: {:?}\n", args);
-            v = args.encode_utf16().collect::<Vec<u16>>()
-        });
-        v
+    // Apply the log filter passed via the command line, if any, e.g., efilite.loglevel=debug.
+    // If the argument appears more than once, the last occurrence wins.
+    let spec = fdt.chosen().bootargs().and_then(|a| {
+        a.split_whitespace()
+            .rev()
+            .find_map(|t| t.strip_prefix(LOGLEVEL_ARG))
+    });
+    let have_cmdline_filter = match spec.map(|s| (s, logfilter::LogFilter::parse(s))) {
+        Some((_, Some(f))) => {
+            logger.set_filter(f);
+            true
+        }
+        Some((s, None)) => {
+            log::warn!("Ignoring invalid log filter {:?}\n", s);
+            false
+        }
+        None => false,
     };
 
     let mut mapper = mapper::MemoryMapper::new();
@@ -146,8 +195,57 @@ extern "C" fn efilite_main(base: *mut u8, used: isize, avail: usize) {
         .flatten()
         .expect("QEMU fwcfg node not found or unusable");
 
-    // Check whether fwcfg exposes a kernel image - no need to proceed otherwise
-    let kloader = fwcfg.get_kernel_loader().expect("No kernel image provided");
+    // Apply the configuration provided by the host, if any
+    let config = config::Config::from_fwcfg(fwcfg);
+    // The command line takes precedence over the configuration file
+    if let (Some(f), false) = (config.log_filter, have_cmdline_filter) {
+        logger.set_filter(f);
+    }
+
+    // Grab the command line from DT, or from fwcfg if the DT does not provide one, and append
+    // any arguments that the host passed separately. Convert the result to UTF-16.
+    let cmdline = {
+        let mut s = match fdt.chosen().bootargs().filter(|a| !a.is_empty()) {
+            Some(args) => {
+                info!("Using command line from /chosen/bootargs: {:?}\n", args);
+                String::from(args)
+            }
+            None => fwcfg
+                .get_cmdline()
+                .map(|args| {
+                    info!("Using command line from fwcfg: {:?}\n", args);
+                    args
+                })
+                .unwrap_or_default(),
+        };
+        if let Some(args) = fwcfg.get_cmdline_append() {
+            info!("Appending to command line: {:?}\n", args);
+            if !s.is_empty() {
+                s.push(' ');
+            }
+            s.push_str(&args);
+        }
+        s.encode_utf16().collect::<Vec<u16>>()
+    };
+
+    // Check whether fwcfg exposes a kernel image or any of the other boot sources - no need to
+    // proceed otherwise
+    let defaults: Vec<&str> = match &config.boot_sources {
+        Some(v) => v.iter().map(String::as_str).collect(),
+        None => DEFAULT_BOOT_SOURCES.to_vec(),
+    };
+    let loaders: Vec<_> = boot::boot_sources(fwcfg, &defaults)
+        .into_iter()
+        .filter_map(|s| {
+            let l = fwcfg.get_boot_loader(&s)?;
+            Some((s, l))
+        })
+        .collect();
+    if loaders.is_empty() {
+        error!("No kernel image or other boot source provided\n");
+        boot::handle_boot_failure(fwcfg);
+        return;
+    }
 
     // Create a new EFI memory map
     let memmap = MemoryMap::new();
@@ -210,10 +308,14 @@ extern "C" fn efilite_main(base: *mut u8, used: isize, avail: usize) {
     // memory that is covered by the static initial mapping in NOR flash. This ensures that we can
     // deactivate/activate the IdMap in calls into the EFI memory attributes protocol.
     const BSPOOL_SIZE: usize = 1024 * 1024;
+    let size = pool_size(config.bs_pool_size, BSPOOL_SIZE);
     unsafe {
         static mut BSPOOL: [MaybeUninit<u8>; BSPOOL_SIZE] = [MaybeUninit::uninit(); BSPOOL_SIZE];
         memmap
-            .declare_pool(EfiBootServicesData, &mut *addr_of_mut!(BSPOOL))
+            .declare_pool(
+                EfiBootServicesData,
+                slice::from_raw_parts_mut(addr_of_mut!(BSPOOL).cast(), size),
+            )
             .expect("Failed to declare memory pool");
     }
 
@@ -230,11 +332,15 @@ extern "C" fn efilite_main(base: *mut u8, used: isize, avail: usize) {
     // and runtime services table) by a statically allocated region which is covered by the static
     // initial mapping in NOR flash.
     const RTPOOL_SIZE: usize = 32 * 1024;
+    let size = pool_size(config.rt_pool_size, RTPOOL_SIZE);
     unsafe {
         #[link_section = ".rtdata"]
         static mut RTPOOL: [MaybeUninit<u8>; RTPOOL_SIZE] = [MaybeUninit::uninit(); RTPOOL_SIZE];
         memmap
-            .declare_pool(EfiRuntimeServicesData, &mut *addr_of_mut!(RTPOOL))
+            .declare_pool(
+                EfiRuntimeServicesData,
+                slice::from_raw_parts_mut(addr_of_mut!(RTPOOL).cast(), size),
+            )
             .expect("Failed to declare memory pool");
     }
 
@@ -242,11 +348,21 @@ extern "C" fn efilite_main(base: *mut u8, used: isize, avail: usize) {
     let rng = Some(rng::Random::new());
     let efi = efiloader::init(con, memmap, mapper, rng).expect("Failed to init EFI runtime");
 
+    // Move the log into memory that the OS will preserve, and tell it where to find it
+    if let Some(log) = logger.publish(efi) {
+        efi.install_configtable(&EFILITE_LOG_GUID, log as *const ());
+    } else {
+        log::warn!("Failed to publish firmware log\n");
+    }
+
     // Register our PSCI based ResetSystem implementation
     efi.override_reset_handler(psci::reset_system);
 
-    // Try loading the ACPI tables from QEMU
-    let tbl = fwcfg.load_firmware_tables(efi);
+    // Try loading the ACPI tables from QEMU, unless the configuration tells us not to
+    let tbl = match config.acpi {
+        Some(false) => Err("disabled by configuration"),
+        _ => fwcfg.load_firmware_tables(efi),
+    };
     if let Ok(rsdp) = tbl {
         info!("Booting in ACPI mode\n");
         efi.install_configtable(&RSDP_GUID, rsdp as *const ());
@@ -263,21 +379,87 @@ extern "C" fn efilite_main(base: *mut u8, used: isize, avail: usize) {
         efi.install_configtable(&DTB_GUID, dtb.start as *const ());
     }
 
-    if let Ok(anchor) = fwcfg.load_smbios_tables(efi) {
-        info!("Installing SMBIOS tables\n");
-        efi.install_configtable(&SMBIOS3_GUID, anchor as *const ());
+    // Generate a minimal set of SMBIOS tables if the host does not provide any
+    let smbios = if fwcfg.has_smbios_tables() {
+        fwcfg.load_smbios_tables(efi)
+    } else {
+        info!("No SMBIOS tables provided by the host, generating them from the DT\n");
+        smbios::generate(&fdt, efi).map(fwcfg::SmbiosAnchor::Smbios3)
+    };
+    match smbios {
+        Ok(fwcfg::SmbiosAnchor::Smbios(anchor)) => {
+            info!("Installing SMBIOS tables\n");
+            efi.install_configtable(&SMBIOS_GUID, anchor as *const ());
+        }
+        Ok(fwcfg::SmbiosAnchor::Smbios3(anchor)) => {
+            info!("Installing SMBIOS3 tables\n");
+            efi.install_configtable(&SMBIOS3_GUID, anchor as *const ());
+        }
+        Err(e) => log::warn!("Failed to install SMBIOS tables: {}\n", e),
+    }
+
+    // The initrd is only loaded by the OS after we have handed over control, so check its digest
+    // here, rather than exposing data that the OS will fail to load
+    if let Some(initrd) = fwcfg.get_initrd_loader() {
+        match initrd.verify() {
+            Ok(()) => efi.set_initrd_loader(initrd),
+            Err(e) => error!("Not exposing initrd to the OS: {}\n", e),
+        }
     }
 
-    fwcfg.get_initrd_loader().map(|i| efi.set_initrd_loader(i));
+    // Expose the fwcfg files to the loaded image via the Simple File System protocol
+    fwcfgfs::install(fwcfg, efi);
+    info!("Exposing fwcfg files via the Simple File System protocol\n");
+
+    // Try each boot source in turn until one of them can be loaded and runs to completion
+    let mut booted = false;
+    for (source, kloader) in loaders.iter() {
+        info!("Booting from {}\n", source);
+        // Check the digest before loading the image, as efiloader cannot unload it again
+        if let Err(e) = kloader.verify() {
+            error!("Refusing to boot from {}: {}\n", source, e);
+            continue;
+        }
+        let start = timer::counter();
+        if let Some(mut li) = efi.load_image(kloader) {
+            debug!(
+                "Loaded image in {} us\n",
+                timer::ticks_to_us(timer::counter() - start)
+            );
+            fwcfg.log_stats();
+            li.set_load_options(cmdline.clone());
+
+            info!("Starting loaded EFI program\n");
+            let ret = li.start_image();
+            info!("EFI program exited with return value {:?}\n", ret);
+            if let status::Status::EFI_SUCCESS = ret {
+                booted = true;
+                break;
+            }
+        } else {
+            info!("Failed to load image\n");
+        }
+    }
 
-    if let Some(mut li) = efi.load_image(&kloader) {
-        li.set_load_options(cmdline);
+    if !booted {
+        boot::handle_boot_failure(fwcfg);
+    }
+}
 
-        info!("Starting loaded EFI program\n");
-        let ret = li.start_image();
-        info!("EFI program exited with return value {:?}\n", ret);
-    } else {
-        info!("Failed to load image\n");
+// The pools are backed by static allocations, so the configured size can only be used to limit
+// how much of that is actually used.
+fn pool_size(size: Option<usize>, max: usize) -> usize {
+    match size {
+        Some(s) if s > max => {
+            log::warn!(
+                "Pool size {} exceeds static reservation of {} bytes\n",
+                s,
+                max
+            );
+            max
+        }
+        Some(s) => (s & !(EFI_PAGE_SIZE - 1)).max(EFI_PAGE_SIZE),
+        None => max,
     }
 }
 
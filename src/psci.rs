@@ -21,7 +21,7 @@ fn poweroff() -> ! {
     loop {}
 }
 
-fn reboot() -> ! {
+pub fn reboot() -> ! {
     psci_call(PSCI_SYSTEM_RESET);
     loop {}
 }
@@ -0,0 +1,136 @@
+// SPDX-License-Identifier: GPL-2.0
+// Copyright 2026 Google LLC
+
+//! A straight-forward implementation of SHA-256 as specified in FIPS 180-4
+
+pub const SHA256_DIGEST_SIZE: usize = 32;
+
+const BLOCK_SIZE: usize = 64;
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+const H0: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+#[derive(Clone)]
+pub struct Sha256 {
+    state: [u32; 8],
+    buf: [u8; BLOCK_SIZE],
+    buflen: usize,
+    len: u64,
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sha256 {
+    pub fn new() -> Self {
+        Sha256 {
+            state: H0,
+            buf: [0; BLOCK_SIZE],
+            buflen: 0,
+            len: 0,
+        }
+    }
+
+    fn compress(state: &mut [u32; 8], block: &[u8]) {
+        let mut w = [0u32; 64];
+        for (w, b) in w.iter_mut().zip(block.chunks_exact(4)) {
+            *w = u32::from_be_bytes(b.try_into().unwrap());
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+        for (k, w) in K.iter().zip(w) {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(*k)
+                .wrapping_add(w);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+
+        for (s, v) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+
+        // Complete a partial block left over from a previous call first
+        if self.buflen > 0 {
+            let n = data.len().min(BLOCK_SIZE - self.buflen);
+            self.buf[self.buflen..self.buflen + n].copy_from_slice(&data[..n]);
+            self.buflen += n;
+            data = &data[n..];
+            if self.buflen < BLOCK_SIZE {
+                return;
+            }
+            Self::compress(&mut self.state, &self.buf);
+            self.buflen = 0;
+        }
+
+        let mut blocks = data.chunks_exact(BLOCK_SIZE);
+        for b in blocks.by_ref() {
+            Self::compress(&mut self.state, b);
+        }
+        let rem = blocks.remainder();
+        self.buf[..rem.len()].copy_from_slice(rem);
+        self.buflen = rem.len();
+    }
+
+    pub fn finalize(mut self) -> [u8; SHA256_DIGEST_SIZE] {
+        let bits = self.len.wrapping_mul(8);
+
+        // Append the 0x80 terminator, zero padding and the message length in bits
+        let mut pad = [0u8; 2 * BLOCK_SIZE];
+        pad[0] = 0x80;
+        let padlen = if self.buflen < BLOCK_SIZE - 8 {
+            BLOCK_SIZE - self.buflen
+        } else {
+            2 * BLOCK_SIZE - self.buflen
+        };
+        pad[padlen - 8..padlen].copy_from_slice(&bits.to_be_bytes());
+        self.update(&pad[..padlen]);
+
+        let mut digest = [0u8; SHA256_DIGEST_SIZE];
+        for (d, s) in digest.chunks_exact_mut(4).zip(self.state) {
+            d.copy_from_slice(&s.to_be_bytes());
+        }
+        digest
+    }
+}
@@ -0,0 +1,429 @@
+// SPDX-License-Identifier: GPL-2.0
+// Copyright 2026 Google LLC
+
+//! Minimal SMBIOS 3.0 tables synthesized from the device tree, for use when the host does not
+//! provide any SMBIOS tables via fw_cfg.
+
+use crate::version;
+
+use alloc::vec::Vec;
+use core::arch::asm;
+use efiloader::memmap::{self, Placement};
+use efiloader::memorytype::EfiMemoryType;
+use efiloader::EfiContext;
+use fdt::Fdt;
+
+const SMBIOS3_ANCHOR_SIZE: usize = 24;
+
+const SMBIOS_TYPE_BIOS_INFORMATION: u8 = 0;
+const SMBIOS_TYPE_SYSTEM_INFORMATION: u8 = 1;
+const SMBIOS_TYPE_SYSTEM_ENCLOSURE: u8 = 3;
+const SMBIOS_TYPE_PROCESSOR_INFORMATION: u8 = 4;
+const SMBIOS_TYPE_PHYSICAL_MEMORY_ARRAY: u8 = 16;
+const SMBIOS_TYPE_MEMORY_DEVICE: u8 = 17;
+const SMBIOS_TYPE_MEMORY_ARRAY_MAPPED_ADDRESS: u8 = 19;
+const SMBIOS_TYPE_END_OF_TABLE: u8 = 127;
+
+// Handle value used for optional references to structures that are not provided
+const SMBIOS_HANDLE_NONE: u16 = 0xfffe;
+
+// A single SMBIOS structure: the formatted area followed by its string set
+struct Record {
+    data: Vec<u8>,
+    strings: Vec<u8>,
+    count: u8,
+}
+
+impl Record {
+    fn new(rtype: u8) -> Self {
+        // The length and handle fields are filled in when the record is added to the table
+        Record {
+            data: Vec::from([rtype, 0, 0, 0]),
+            strings: Vec::new(),
+            count: 0,
+        }
+    }
+
+    fn u8(mut self, v: u8) -> Self {
+        self.data.push(v);
+        self
+    }
+
+    fn u16(mut self, v: u16) -> Self {
+        self.data.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn u32(mut self, v: u32) -> Self {
+        self.data.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn u64(mut self, v: u64) -> Self {
+        self.data.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn bytes(mut self, v: &[u8]) -> Self {
+        self.data.extend_from_slice(v);
+        self
+    }
+
+    // Empty strings are not permitted in the string set, and are referenced as string #0
+    fn str(mut self, s: &str) -> Self {
+        if s.is_empty() {
+            return self.u8(0);
+        }
+        self.count += 1;
+        self.strings.extend(s.bytes().filter(|b| *b != 0));
+        self.strings.push(0);
+        let n = self.count;
+        self.u8(n)
+    }
+}
+
+#[derive(Default)]
+struct Table {
+    data: Vec<u8>,
+    count: u16,
+}
+
+impl Table {
+    // Append a record to the table, and return the handle that was assigned to it
+    fn add(&mut self, r: Record) -> u16 {
+        let handle = self.count;
+        self.push(r, handle);
+        handle
+    }
+
+    fn push(&mut self, mut r: Record, handle: u16) {
+        self.count += 1;
+
+        r.data[1] = r.data.len() as u8;
+        r.data[2..4].copy_from_slice(&handle.to_le_bytes());
+        self.data.extend_from_slice(&r.data);
+        self.data.extend_from_slice(&r.strings);
+
+        // The string set is terminated by a double NUL, even if it is empty
+        if r.strings.is_empty() {
+            self.data.push(0);
+        }
+        self.data.push(0);
+    }
+}
+
+fn midr() -> u64 {
+    let mut l: u64;
+    unsafe {
+        asm!(
+            "mrs {reg}, midr_el1",
+            reg = out(reg) l,
+            options(pure, nomem, nostack, preserves_flags)
+        );
+    }
+    l
+}
+
+fn bios_information() -> Record {
+    Record::new(SMBIOS_TYPE_BIOS_INFORMATION)
+        .str(version::VENDOR)
+        .str(&version::version_string())
+        .u16(0) // BIOS starting address segment - not applicable
+        .str("") // BIOS release date
+        .u8(0) // BIOS ROM size
+        .u64(1 << 3) // BIOS characteristics are not supported
+        .u8(0) // BIOS characteristics extension byte 1
+        .u8((1 << 3) | (1 << 4)) // UEFI is supported, this is a virtual machine
+        .u8(0xff) // System BIOS major release - not supported
+        .u8(0xff) // System BIOS minor release - not supported
+        .u8(0xff) // Embedded controller major release - not supported
+        .u8(0xff) // Embedded controller minor release - not supported
+        .u16(0) // Extended BIOS ROM size
+}
+
+fn system_information(fdt: &Fdt) -> Record {
+    let model = fdt.root().property("model").and_then(|p| p.as_str());
+
+    Record::new(SMBIOS_TYPE_SYSTEM_INFORMATION)
+        .str("QEMU") // Manufacturer
+        .str(model.unwrap_or("")) // Product name
+        .str("") // Version
+        .str("") // Serial number
+        .bytes(&[0u8; 16]) // UUID - present but not set
+        .u8(6) // Wake-up type - power switch
+        .str("") // SKU number
+        .str("") // Family
+}
+
+fn system_enclosure() -> Record {
+    Record::new(SMBIOS_TYPE_SYSTEM_ENCLOSURE)
+        .str("QEMU") // Manufacturer
+        .u8(1) // Type - other
+        .str("") // Version
+        .str("") // Serial number
+        .str("") // Asset tag number
+        .u8(3) // Boot-up state - safe
+        .u8(3) // Power supply state - safe
+        .u8(3) // Thermal state - safe
+        .u8(2) // Security status - unknown
+        .u32(0) // OEM-defined
+        .u8(0) // Height - unspecified
+        .u8(0) // Number of power cords - unspecified
+        .u8(0) // Contained element count
+        .u8(0) // Contained element record length
+        .str("") // SKU number
+}
+
+fn processor_information(socket: &str, compatible: &str) -> Record {
+    // Use the vendor prefix of the compatible string (e.g., "arm,cortex-a57") as the manufacturer
+    let manufacturer = compatible.split_once(',').map_or("", |(m, _)| m);
+
+    Record::new(SMBIOS_TYPE_PROCESSOR_INFORMATION)
+        .str(socket) // Socket designation
+        .u8(3) // Processor type - central processor
+        .u8(0xfe) // Processor family - see processor family 2
+        .str(manufacturer) // Processor manufacturer
+        .u64(midr() & u32::MAX as u64) // Processor ID - MIDR_EL1
+        .str(compatible) // Processor version
+        .u8(0) // Voltage - unknown
+        .u16(0) // External clock - unknown
+        .u16(0) // Max speed - unknown
+        .u16(0) // Current speed - unknown
+        .u8(0x41) // Status - socket populated, CPU enabled
+        .u8(6) // Processor upgrade - none
+        .u16(0xffff) // L1 cache handle - not provided
+        .u16(0xffff) // L2 cache handle - not provided
+        .u16(0xffff) // L3 cache handle - not provided
+        .str("") // Serial number
+        .str("") // Asset tag
+        .str("") // Part number
+        .u8(1) // Core count
+        .u8(1) // Core enabled
+        .u8(1) // Thread count
+        .u16(1 << 2) // Processor characteristics - 64-bit capable
+        .u16(0x101) // Processor family 2 - ARMv8
+        .u16(1) // Core count 2
+        .u16(1) // Core enabled 2
+        .u16(1) // Thread count 2
+}
+
+fn physical_memory_array(capacity: u64, devices: u16) -> Record {
+    // Capacities of 2 TiB or more must be described using the extended field
+    let kb = capacity / 1024;
+    let (max, ext) = match u32::try_from(kb) {
+        Ok(kb) if kb < 0x8000_0000 => (kb, 0),
+        _ => (0x8000_0000, capacity),
+    };
+
+    Record::new(SMBIOS_TYPE_PHYSICAL_MEMORY_ARRAY)
+        .u8(3) // Location - system board
+        .u8(3) // Use - system memory
+        .u8(3) // Memory error correction - none
+        .u32(max) // Maximum capacity
+        .u16(SMBIOS_HANDLE_NONE) // Memory error information handle
+        .u16(devices) // Number of memory devices
+        .u64(ext) // Extended maximum capacity
+}
+
+fn memory_device(array: u16, size: u64, locator: &str) -> Record {
+    // Sizes of 32 GiB - 1 MiB or more must be described using the extended field
+    let mb = size / (1024 * 1024);
+    let (size, ext) = if mb < 0x7fff {
+        (mb as u16, 0)
+    } else {
+        (0x7fff, mb.min(0x7fff_ffff) as u32)
+    };
+
+    Record::new(SMBIOS_TYPE_MEMORY_DEVICE)
+        .u16(array) // Physical memory array handle
+        .u16(SMBIOS_HANDLE_NONE) // Memory error information handle
+        .u16(0xffff) // Total width - unknown
+        .u16(0xffff) // Data width - unknown
+        .u16(size) // Size in MiB
+        .u8(1) // Form factor - other
+        .u8(0) // Device set - none
+        .str(locator) // Device locator
+        .str("") // Bank locator
+        .u8(7) // Memory type - RAM
+        .u16(1 << 1) // Type detail - other
+        .u16(0) // Speed - unknown
+        .str("QEMU") // Manufacturer
+        .str("") // Serial number
+        .str("") // Asset tag
+        .str("") // Part number
+        .u8(0) // Attributes - unknown rank
+        .u32(ext) // Extended size in MiB
+        .u16(0) // Configured memory speed - unknown
+        .u16(0) // Minimum voltage - unknown
+        .u16(0) // Maximum voltage - unknown
+        .u16(0) // Configured voltage - unknown
+}
+
+fn memory_array_mapped_address(array: u16, base: u64, size: u64) -> Record {
+    // Ranges that extend beyond 4 TiB must be described using the extended fields
+    let (start, end) = (base, base + size - 1);
+    let (kb_start, kb_end, ext_start, ext_end) = if end / 1024 < u32::MAX as u64 {
+        ((start / 1024) as u32, (end / 1024) as u32, 0, 0)
+    } else {
+        (u32::MAX, u32::MAX, start, end)
+    };
+
+    Record::new(SMBIOS_TYPE_MEMORY_ARRAY_MAPPED_ADDRESS)
+        .u32(kb_start) // Starting address in KiB
+        .u32(kb_end) // Ending address in KiB
+        .u16(array) // Memory array handle
+        .u8(1) // Partition width
+        .u64(ext_start) // Extended starting address
+        .u64(ext_end) // Extended ending address
+}
+
+fn build_tables(fdt: &Fdt) -> Vec<u8> {
+    let mut t = Table::default();
+
+    t.add(bios_information());
+    t.add(system_information(fdt));
+    t.add(system_enclosure());
+
+    if let Some(cpus) = fdt.find_node("/cpus") {
+        for cpu in cpus
+            .children()
+            .filter(|n| n.property("device_type").and_then(|p| p.as_str()) == Some("cpu"))
+        {
+            let compatible = cpu.compatible().map_or("", |c| c.first());
+            t.add(processor_information(cpu.name, compatible));
+        }
+    }
+
+    let regions: Vec<(u64, u64)> = fdt
+        .memory()
+        .regions()
+        .filter_map(|r| Some((r.starting_address as u64, r.size.filter(|s| *s > 0)? as u64)))
+        .collect();
+
+    let capacity: u64 = regions.iter().map(|(_, s)| s).sum();
+    let array = t.add(physical_memory_array(capacity, regions.len() as u16));
+    for (i, (base, size)) in regions.iter().enumerate() {
+        t.add(memory_device(array, *size, &alloc::format!("DIMM {}", i)));
+        t.add(memory_array_mapped_address(array, *base, *size));
+    }
+
+    t.add(Record::new(SMBIOS_TYPE_END_OF_TABLE));
+    t.data
+}
+
+// Split off the first structure of a table blob, and return its formatted area, its string set
+// (including the terminating double NUL) and the remainder of the blob.
+fn split_structure(t: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    let len = *t.get(1)? as usize;
+    if len < 4 || len > t.len() {
+        return None;
+    }
+    let (formatted, rest) = t.split_at(len);
+    let end = rest.windows(2).position(|w| w == [0, 0])? + 2;
+    let (strings, rest) = rest.split_at(end);
+    Some((formatted, strings, rest))
+}
+
+// Rebuild a type 0 structure with our vendor and version strings, keeping everything else
+fn stamp_structure(formatted: &[u8], strings: &[u8]) -> Vec<u8> {
+    let version = version::version_string();
+    let mut set: Vec<&[u8]> = strings
+        .split(|b| *b == 0)
+        .filter(|s| !s.is_empty())
+        .collect();
+    let mut data = formatted.to_vec();
+
+    for (offset, s) in [(4, version::VENDOR.as_bytes()), (5, version.as_bytes())] {
+        match data[offset] as usize {
+            // Don't clobber a string that is shared with the vendor field
+            i if i > 0 && i <= set.len() && (offset == 4 || data[4] != data[5]) => set[i - 1] = s,
+            _ => {
+                set.push(s);
+                data[offset] = set.len() as u8;
+            }
+        }
+    }
+
+    for s in set.iter() {
+        data.extend_from_slice(s);
+        data.push(0);
+    }
+    if set.is_empty() {
+        data.push(0);
+    }
+    data.push(0);
+    data
+}
+
+/// Rewrite the SMBIOS type 0 (BIOS information) record in a host provided table blob so that it
+/// identifies this firmware build, or insert one if the host did not provide it. Returns the new
+/// blob, the number of structures and the size of the largest structure, or None if the blob
+/// could not be parsed or has no free handle left for the inserted record.
+pub fn stamp_bios_information(tables: &[u8]) -> Option<(Vec<u8>, u16, u16)> {
+    let mut structures = Vec::new();
+    let mut t = tables;
+    while !t.is_empty() {
+        let (formatted, strings, rest) = split_structure(t)?;
+        structures.push((formatted, strings));
+        t = rest;
+    }
+
+    let mut out = Table::default();
+    if !structures
+        .iter()
+        .any(|(f, _)| f[0] == SMBIOS_TYPE_BIOS_INFORMATION)
+    {
+        // Pick the lowest handle that is not in use, avoiding the reserved range at the top
+        let handles: Vec<u16> = structures
+            .iter()
+            .map(|(f, _)| u16::from_le_bytes([f[2], f[3]]))
+            .collect();
+        let handle = (0..0xFF00).find(|h| !handles.contains(h))?;
+        out.push(bios_information(), handle);
+    }
+
+    let mut max = out.data.len();
+    for (f, s) in structures {
+        let len = out.data.len();
+        if f[0] == SMBIOS_TYPE_BIOS_INFORMATION && f.len() > 5 {
+            out.data.extend_from_slice(&stamp_structure(f, s));
+        } else {
+            out.data.extend_from_slice(f);
+            out.data.extend_from_slice(s);
+        }
+        out.count += 1;
+        max = max.max(out.data.len() - len);
+    }
+    Some((out.data, out.count, max as u16))
+}
+
+/// Synthesize a minimal set of SMBIOS 3.0 tables describing the firmware, the CPUs and the
+/// memory found in the DT, and return a pointer to the SMBIOS3 entry point structure
+pub fn generate(fdt: &Fdt, efi: &EfiContext) -> Result<*const u8, &'static str> {
+    let tables = build_tables(fdt);
+
+    let b = efi
+        .allocate_pages(
+            memmap::size_to_pages(SMBIOS3_ANCHOR_SIZE + tables.len()),
+            EfiMemoryType::EfiACPIReclaimMemory,
+            Placement::Anywhere,
+        )
+        .ok_or("Failed to allocate SMBIOS table memory")?;
+    let b = &mut b[..SMBIOS3_ANCHOR_SIZE + tables.len()];
+
+    let mut anchor = Vec::with_capacity(SMBIOS3_ANCHOR_SIZE);
+    anchor.extend_from_slice(b"_SM3_");
+    anchor.push(0); // Checksum
+    anchor.push(SMBIOS3_ANCHOR_SIZE as u8); // Entry point length
+    anchor.extend_from_slice(&[3, 0, 0]); // SMBIOS version 3.0.0
+    anchor.push(1); // Entry point revision
+    anchor.push(0); // Reserved
+    anchor.extend_from_slice(&(tables.len() as u32).to_le_bytes()); // Structure table max size
+    anchor.extend_from_slice(&(b[SMBIOS3_ANCHOR_SIZE..].as_ptr() as u64).to_le_bytes());
+    anchor[5] = anchor.iter().fold(0u8, |sum, c| sum.wrapping_sub(*c));
+
+    for (d, s) in b.iter_mut().zip(anchor.iter().chain(tables.iter())) {
+        d.write(*s);
+    }
+    Ok(b.as_ptr() as *const u8)
+}
@@ -0,0 +1,47 @@
+// SPDX-License-Identifier: GPL-2.0
+// Copyright 2026 Google LLC
+
+use core::arch::asm;
+
+/// Read the virtual counter of the generic timer
+pub fn counter() -> u64 {
+    let mut l: u64;
+    unsafe {
+        asm!(
+            "isb",
+            "mrs {reg}, cntvct_el0",
+            reg = out(reg) l,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+    l
+}
+
+fn frequency() -> u64 {
+    let mut l: u64;
+    unsafe {
+        asm!(
+            "mrs {reg}, cntfrq_el0",
+            reg = out(reg) l,
+            options(pure, nomem, nostack, preserves_flags)
+        );
+    }
+    l
+}
+
+/// Busy wait for the given number of milliseconds
+pub fn delay_ms(ms: u32) {
+    let ticks = frequency() * ms as u64 / 1000;
+    let start = counter();
+    while counter() - start < ticks {
+        core::hint::spin_loop();
+    }
+}
+
+/// Convert a number of counter ticks into microseconds
+pub fn ticks_to_us(ticks: u64) -> u64 {
+    match frequency() {
+        0 => 0,
+        f => (ticks as u128 * 1_000_000 / f as u128) as u64,
+    }
+}
@@ -0,0 +1,14 @@
+// SPDX-License-Identifier: GPL-2.0
+// Copyright 2026 Google LLC
+
+use alloc::format;
+use alloc::string::String;
+
+pub const VENDOR: &str = "efilite";
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const BUILD_ID: &str = env!("EFILITE_BUILD_ID");
+
+/// The crate version and build ID in human readable form, e.g., "0.1.0 (v0.1.0-12-gdeadbeef1234)"
+pub fn version_string() -> String {
+    format!("{} ({})", VERSION, BUILD_ID)
+}
@@ -0,0 +1,406 @@
+// SPDX-License-Identifier: GPL-2.0
+// Copyright 2026 Google LLC
+
+//! Minimal virtio-mmio transport and virtio-console driver, for use as the firmware console on
+//! VMs that do not provide an emulated UART. Both the legacy (version 1) and the modern
+//! (version 2) virtio-mmio register layouts are supported.
+
+use crate::timer;
+
+use core::mem::MaybeUninit;
+use core::ptr::{addr_of, addr_of_mut};
+use core::sync::atomic::{fence, Ordering};
+use fdt::node::FdtNode;
+use fdt::Fdt;
+
+const VIRTIO_MMIO_MAGIC_VALUE: usize = 0x000;
+const VIRTIO_MMIO_VERSION: usize = 0x004;
+const VIRTIO_MMIO_DEVICE_ID: usize = 0x008;
+const VIRTIO_MMIO_DEVICE_FEATURES: usize = 0x010;
+const VIRTIO_MMIO_DEVICE_FEATURES_SEL: usize = 0x014;
+const VIRTIO_MMIO_DRIVER_FEATURES: usize = 0x020;
+const VIRTIO_MMIO_DRIVER_FEATURES_SEL: usize = 0x024;
+const VIRTIO_MMIO_GUEST_PAGE_SIZE: usize = 0x028;
+const VIRTIO_MMIO_QUEUE_SEL: usize = 0x030;
+const VIRTIO_MMIO_QUEUE_NUM_MAX: usize = 0x034;
+const VIRTIO_MMIO_QUEUE_NUM: usize = 0x038;
+const VIRTIO_MMIO_QUEUE_ALIGN: usize = 0x03c;
+const VIRTIO_MMIO_QUEUE_PFN: usize = 0x040;
+const VIRTIO_MMIO_QUEUE_READY: usize = 0x044;
+const VIRTIO_MMIO_QUEUE_NOTIFY: usize = 0x050;
+const VIRTIO_MMIO_STATUS: usize = 0x070;
+const VIRTIO_MMIO_QUEUE_DESC_LOW: usize = 0x080;
+const VIRTIO_MMIO_QUEUE_DESC_HIGH: usize = 0x084;
+const VIRTIO_MMIO_QUEUE_DRIVER_LOW: usize = 0x090;
+const VIRTIO_MMIO_QUEUE_DRIVER_HIGH: usize = 0x094;
+const VIRTIO_MMIO_QUEUE_DEVICE_LOW: usize = 0x0a0;
+const VIRTIO_MMIO_QUEUE_DEVICE_HIGH: usize = 0x0a4;
+
+const VIRTIO_MMIO_MAGIC: u32 = 0x74726976; // "virt"
+
+const VIRTIO_ID_CONSOLE: u32 = 3;
+
+const VIRTIO_STATUS_ACKNOWLEDGE: u32 = 1;
+const VIRTIO_STATUS_DRIVER: u32 = 2;
+const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
+const VIRTIO_STATUS_FEATURES_OK: u32 = 8;
+const VIRTIO_STATUS_FAILED: u32 = 128;
+
+// VIRTIO_F_VERSION_1 is feature bit #32, i.e., bit #0 of the second feature word
+const VIRTIO_F_VERSION_1_HI: u32 = 1 << 0;
+
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+const VIRTIO_PAGE_SIZE: usize = 4096;
+
+// The console's receive and transmit queues for port #0
+const VIRTIO_CONSOLE_RX_QUEUE: u32 = 0;
+const VIRTIO_CONSOLE_TX_QUEUE: u32 = 1;
+
+const QUEUE_SIZE: usize = 8;
+const RX_BUF_SIZE: usize = 64;
+const TX_BUF_SIZE: usize = 256;
+
+// How long to wait for the host to consume a transmit buffer before giving up on the device
+const TX_TIMEOUT_US: u64 = 100_000;
+
+#[repr(C)]
+struct VirtqDesc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct VirtqAvail {
+    flags: u16,
+    idx: u16,
+    ring: [u16; QUEUE_SIZE],
+    used_event: u16,
+}
+
+#[repr(C)]
+struct VirtqUsedElem {
+    id: u32,
+    len: u32,
+}
+
+// The legacy interface requires the used ring to start on the next page boundary
+#[repr(C, align(4096))]
+struct VirtqUsed {
+    flags: u16,
+    idx: u16,
+    ring: [VirtqUsedElem; QUEUE_SIZE],
+    avail_event: u16,
+}
+
+#[repr(C, align(4096))]
+struct Virtq {
+    desc: [VirtqDesc; QUEUE_SIZE],
+    avail: VirtqAvail,
+    used: VirtqUsed,
+}
+
+#[repr(C)]
+struct VirtioConsoleMem {
+    rxq: Virtq,
+    txq: Virtq,
+    rx: [[u8; RX_BUF_SIZE]; QUEUE_SIZE],
+    tx: [u8; TX_BUF_SIZE],
+}
+
+pub struct VirtioConsole {
+    base: usize,
+    mem: *mut VirtioConsoleMem,
+
+    // the number of buffers we have posted to each queue so far
+    rx_avail: u16,
+    tx_avail: u16,
+    // the number of received buffers we have consumed so far
+    rx_used: u16,
+
+    // the receive buffer we are currently returning data from, and our position inside it
+    rx_pending: Option<(usize, usize, usize)>,
+    // the number of bytes in the transmit buffer that have not been submitted yet
+    tx_len: usize,
+
+    // set if the host stopped consuming output, so that we don't stall on every write
+    broken: bool,
+}
+
+// SAFETY: new() may only be called once, so the VirtioConsole instance is the sole owner of the
+// queue memory that mem points to, and moving it to another context cannot result in shared
+// access to that memory.
+unsafe impl Send for VirtioConsole {}
+
+// Check whether the virtio-mmio transport at base exposes a console device
+fn is_console(base: usize) -> bool {
+    let reg = |offset: usize| unsafe { ((base + offset) as *const u32).read_volatile() };
+    reg(VIRTIO_MMIO_MAGIC_VALUE) == VIRTIO_MMIO_MAGIC
+        && matches!(reg(VIRTIO_MMIO_VERSION), 1 | 2)
+        && reg(VIRTIO_MMIO_DEVICE_ID) == VIRTIO_ID_CONSOLE
+}
+
+/// Find the first virtio-mmio transport in the DT that exposes a console device
+pub fn find_console<'b, 'a>(fdt: &'b Fdt<'a>) -> Option<FdtNode<'b, 'a>> {
+    fdt.all_nodes().find(|n| {
+        n.compatible()
+            .is_some_and(|c| c.all().any(|c| c == "virtio,mmio"))
+            && n.reg()
+                .and_then(|mut r| r.next())
+                .is_some_and(|r| is_console(r.starting_address as usize))
+    })
+}
+
+impl VirtioConsole {
+    fn read(&self, offset: usize) -> u32 {
+        // SAFETY: base points to the registers of a virtio-mmio transport
+        unsafe { ((self.base + offset) as *const u32).read_volatile() }
+    }
+
+    fn write(&mut self, offset: usize, val: u32) {
+        // SAFETY: base points to the registers of a virtio-mmio transport
+        unsafe { ((self.base + offset) as *mut u32).write_volatile(val) }
+    }
+
+    fn write_addr(&mut self, low: usize, high: usize, addr: u64) {
+        self.write(low, addr as u32);
+        self.write(high, (addr >> 32) as u32);
+    }
+
+    fn queue(&self, q: u32) -> *mut Virtq {
+        // SAFETY: mem points to our statically allocated queue memory
+        unsafe {
+            match q {
+                VIRTIO_CONSOLE_RX_QUEUE => addr_of_mut!((*self.mem).rxq),
+                _ => addr_of_mut!((*self.mem).txq),
+            }
+        }
+    }
+
+    fn setup_queue(&mut self, q: u32, legacy: bool) -> Option<()> {
+        self.write(VIRTIO_MMIO_QUEUE_SEL, q);
+        if (self.read(VIRTIO_MMIO_QUEUE_NUM_MAX) as usize) < QUEUE_SIZE {
+            return None;
+        }
+        self.write(VIRTIO_MMIO_QUEUE_NUM, QUEUE_SIZE as u32);
+
+        let vq = self.queue(q);
+        if legacy {
+            self.write(VIRTIO_MMIO_QUEUE_ALIGN, VIRTIO_PAGE_SIZE as u32);
+            self.write(
+                VIRTIO_MMIO_QUEUE_PFN,
+                (vq as usize / VIRTIO_PAGE_SIZE) as u32,
+            );
+        } else {
+            // SAFETY: vq points to our statically allocated queue memory
+            let (desc, avail, used) = unsafe {
+                (
+                    addr_of!((*vq).desc) as u64,
+                    addr_of!((*vq).avail) as u64,
+                    addr_of!((*vq).used) as u64,
+                )
+            };
+            self.write_addr(
+                VIRTIO_MMIO_QUEUE_DESC_LOW,
+                VIRTIO_MMIO_QUEUE_DESC_HIGH,
+                desc,
+            );
+            self.write_addr(
+                VIRTIO_MMIO_QUEUE_DRIVER_LOW,
+                VIRTIO_MMIO_QUEUE_DRIVER_HIGH,
+                avail,
+            );
+            self.write_addr(
+                VIRTIO_MMIO_QUEUE_DEVICE_LOW,
+                VIRTIO_MMIO_QUEUE_DEVICE_HIGH,
+                used,
+            );
+            self.write(VIRTIO_MMIO_QUEUE_READY, 1);
+        }
+        Some(())
+    }
+
+    // Expose descriptor #id to the device via the available ring, but don't notify it yet
+    fn push_avail(&mut self, q: u32, id: usize) {
+        let vq = self.queue(q);
+        let idx = match q {
+            VIRTIO_CONSOLE_RX_QUEUE => &mut self.rx_avail,
+            _ => &mut self.tx_avail,
+        };
+        // SAFETY: vq points to our statically allocated queue memory
+        unsafe {
+            addr_of_mut!((*vq).avail.ring[*idx as usize % QUEUE_SIZE]).write_volatile(id as u16);
+            *idx = idx.wrapping_add(1);
+            // Make sure the ring entry is visible to the device before the index update
+            fence(Ordering::SeqCst);
+            addr_of_mut!((*vq).avail.idx).write_volatile(*idx);
+        }
+    }
+
+    fn notify(&mut self, q: u32) {
+        fence(Ordering::SeqCst);
+        self.write(VIRTIO_MMIO_QUEUE_NOTIFY, q);
+    }
+
+    fn used_idx(&self, q: u32) -> u16 {
+        // SAFETY: the queue memory is statically allocated
+        let idx = unsafe { addr_of!((*self.queue(q)).used.idx).read_volatile() };
+        // Don't read any ring entries before the index
+        fence(Ordering::SeqCst);
+        idx
+    }
+
+    fn post_rx_buffer(&mut self, id: usize) {
+        let vq = self.queue(VIRTIO_CONSOLE_RX_QUEUE);
+        // SAFETY: vq and mem point to our statically allocated queue memory
+        unsafe {
+            addr_of_mut!((*vq).desc[id]).write_volatile(VirtqDesc {
+                addr: addr_of!((*self.mem).rx[id]) as u64,
+                len: RX_BUF_SIZE as u32,
+                flags: VIRTQ_DESC_F_WRITE,
+                next: 0,
+            });
+        }
+        self.push_avail(VIRTIO_CONSOLE_RX_QUEUE, id);
+    }
+
+    /// Initialize the virtio console device exposed by the virtio-mmio transport at base
+    ///
+    /// SAFETY: base must point to the registers of a virtio-mmio transport that exposes a
+    /// console device, and this function must only be called once.
+    pub unsafe fn new(base: usize) -> Option<VirtioConsole> {
+        // The queues live in .rtdata so that DMA by the device cannot corrupt memory that
+        // the OS has reclaimed, in case the host sends input before the OS resets the device.
+        #[link_section = ".rtdata"]
+        static mut MEM: MaybeUninit<VirtioConsoleMem> = MaybeUninit::uninit();
+
+        let mem = addr_of_mut!(MEM) as *mut VirtioConsoleMem;
+        mem.write_bytes(0, 1);
+
+        let mut con = VirtioConsole {
+            base,
+            mem,
+            rx_avail: 0,
+            tx_avail: 0,
+            rx_used: 0,
+            rx_pending: None,
+            tx_len: 0,
+            broken: false,
+        };
+
+        let legacy = con.read(VIRTIO_MMIO_VERSION) == 1;
+        let mut status = VIRTIO_STATUS_ACKNOWLEDGE | VIRTIO_STATUS_DRIVER;
+        con.write(VIRTIO_MMIO_STATUS, 0);
+        con.write(VIRTIO_MMIO_STATUS, status);
+
+        // We don't need any of the console specific features, but a modern device requires
+        // VIRTIO_F_VERSION_1 to be negotiated
+        con.write(VIRTIO_MMIO_DRIVER_FEATURES_SEL, 0);
+        con.write(VIRTIO_MMIO_DRIVER_FEATURES, 0);
+        if legacy {
+            con.write(VIRTIO_MMIO_GUEST_PAGE_SIZE, VIRTIO_PAGE_SIZE as u32);
+        } else {
+            con.write(VIRTIO_MMIO_DEVICE_FEATURES_SEL, 1);
+            let features = con.read(VIRTIO_MMIO_DEVICE_FEATURES) & VIRTIO_F_VERSION_1_HI;
+            con.write(VIRTIO_MMIO_DRIVER_FEATURES_SEL, 1);
+            con.write(VIRTIO_MMIO_DRIVER_FEATURES, features);
+
+            status |= VIRTIO_STATUS_FEATURES_OK;
+            con.write(VIRTIO_MMIO_STATUS, status);
+            if features == 0 || con.read(VIRTIO_MMIO_STATUS) & VIRTIO_STATUS_FEATURES_OK == 0 {
+                con.write(VIRTIO_MMIO_STATUS, VIRTIO_STATUS_FAILED);
+                return None;
+            }
+        }
+
+        for q in [VIRTIO_CONSOLE_RX_QUEUE, VIRTIO_CONSOLE_TX_QUEUE] {
+            if con.setup_queue(q, legacy).is_none() {
+                con.write(VIRTIO_MMIO_STATUS, VIRTIO_STATUS_FAILED);
+                return None;
+            }
+        }
+
+        con.write(VIRTIO_MMIO_STATUS, status | VIRTIO_STATUS_DRIVER_OK);
+
+        for id in 0..QUEUE_SIZE {
+            con.post_rx_buffer(id);
+        }
+        con.notify(VIRTIO_CONSOLE_RX_QUEUE);
+        Some(con)
+    }
+
+    /// Submit any buffered output to the device, and wait for it to be consumed
+    pub fn flush(&mut self) {
+        if self.tx_len == 0 || self.broken {
+            self.tx_len = 0;
+            return;
+        }
+
+        let vq = self.queue(VIRTIO_CONSOLE_TX_QUEUE);
+        // SAFETY: vq and mem point to our statically allocated queue memory
+        unsafe {
+            addr_of_mut!((*vq).desc[0]).write_volatile(VirtqDesc {
+                addr: addr_of!((*self.mem).tx) as u64,
+                len: self.tx_len as u32,
+                flags: 0,
+                next: 0,
+            });
+        }
+        self.push_avail(VIRTIO_CONSOLE_TX_QUEUE, 0);
+        self.notify(VIRTIO_CONSOLE_TX_QUEUE);
+        self.tx_len = 0;
+
+        let start = timer::counter();
+        while self.used_idx(VIRTIO_CONSOLE_TX_QUEUE) != self.tx_avail {
+            if timer::ticks_to_us(timer::counter() - start) > TX_TIMEOUT_US {
+                self.broken = true;
+                return;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn write_byte(&mut self, b: u8) {
+        // SAFETY: mem points to our statically allocated buffers
+        unsafe {
+            addr_of_mut!((*self.mem).tx[self.tx_len]).write_volatile(b);
+        }
+        self.tx_len += 1;
+        if self.tx_len == TX_BUF_SIZE || b == b'\n' {
+            self.flush();
+        }
+    }
+
+    pub fn read_byte(&mut self) -> Option<u8> {
+        loop {
+            let (id, pos, len) = match self.rx_pending {
+                Some(p) => p,
+                None => {
+                    if self.used_idx(VIRTIO_CONSOLE_RX_QUEUE) == self.rx_used {
+                        return None;
+                    }
+                    let vq = self.queue(VIRTIO_CONSOLE_RX_QUEUE);
+                    let slot = self.rx_used as usize % QUEUE_SIZE;
+                    // SAFETY: vq points to our statically allocated queue memory
+                    let e = unsafe { addr_of!((*vq).used.ring[slot]).read_volatile() };
+                    self.rx_used = self.rx_used.wrapping_add(1);
+                    let id = e.id as usize % QUEUE_SIZE;
+                    (id, 0, (e.len as usize).min(RX_BUF_SIZE))
+                }
+            };
+
+            if pos < len {
+                self.rx_pending = Some((id, pos + 1, len));
+                // SAFETY: mem points to our statically allocated buffers
+                return Some(unsafe { addr_of!((*self.mem).rx[id][pos]).read_volatile() });
+            }
+
+            // Hand the exhausted buffer back to the device
+            self.rx_pending = None;
+            self.post_rx_buffer(id);
+            self.notify(VIRTIO_CONSOLE_RX_QUEUE);
+        }
+    }
+}